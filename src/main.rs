@@ -1,9 +1,9 @@
 mod modules;
-use std::{env, fs::{self, File}, io::{Read, Write}, net::TcpStream};
+use std::{collections::BTreeSet, env, fs::{self, File}, io::{self, BufReader, Read, Write}, net::TcpStream, ops::RangeInclusive, path::Path, process, time::Instant};
 use rand::{distr::{Alphanumeric, SampleString}};
 use rand::rng;
 
-use crate::modules::{bencode::{decode_bencoded_value, encode_value}, helpers::{download_piece, get_handshake, get_peers}, torrent::{Magnet, Torrent}, value::{Map, Value}};
+use crate::modules::{bencode::{decode_bencoded_ref, decode_bencoded_value, decode_strict, encode_value, Limits, StrictError}, helpers::{connect, download_piece, get_handshake, ExtensionHandshake}, json::{json_str_to_value, value_to_json}, stream::StreamDecoder, value_serde::from_value, torrent::{info_hash_hex, Torrent}, magnet::Magnet, value::{PathSegment, Value, ValueRef}, diff::diff_values, storage::Storage, tracker::TrackerList, create::{create_torrent, CreateOptions}, edit::{edit_torrent, Edit}, validate::{validate_torrent, Severity}, webseed::{HttpSeed, WebSeed}};
 
 fn bytes_to_peer_list(bytes: &[u8]) -> Vec<(String, u16)> {
     let mut i = 0;
@@ -18,44 +18,618 @@ fn bytes_to_peer_list(bytes: &[u8]) -> Vec<(String, u16)> {
 }
 
 fn generate_random_string(length: usize) -> String {
-    Alphanumeric::default().sample_string(&mut rng(), length)
+    Alphanumeric.sample_string(&mut rng(), length)
+}
+
+// Reads command input given as `-f <file>`, `-` for stdin, or the argument itself.
+fn read_input(args: &[String]) -> Vec<u8> {
+    match args {
+        [flag, path] if flag == "-f" => fs::read(path).unwrap_or_else(|err| {
+            eprintln!("Error: can't read {}: {}", path, err);
+            process::exit(1);
+        }),
+        [dash] if dash == "-" => {
+            let mut input = vec![];
+            io::stdin().read_to_end(&mut input).unwrap();
+            input
+        },
+        [value] => value.as_bytes().to_vec(),
+        _ => {
+            eprintln!("Error: expected a value, -f <file> or - for stdin");
+            process::exit(1);
+        }
+    }
+}
+
+// Decodes a bencoded document, printing the decoder diagnostic and exiting on malformed input.
+fn decode_or_exit(encoded_value: &[u8]) -> ValueRef<'_> {
+    match decode_bencoded_ref(encoded_value) {
+        Ok((value, _)) => value,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Like `decode_or_exit`, but also rejects non-canonical encodings, listing every violation found.
+fn decode_strict_or_exit(encoded_value: &[u8]) -> ValueRef<'_> {
+    match decode_strict(encoded_value) {
+        Ok(value) => value,
+        Err(StrictError::NonCanonical(violations)) => {
+            for violation in &violations {
+                eprintln!("Violation: {}", violation);
+            }
+            eprintln!("Error: {} canonical encoding violation(s)", violations.len());
+            process::exit(1);
+        },
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Builds a torrent from decoded metainfo, naming the missing or mistyped field on failure.
+fn torrent_or_exit(value: &ValueRef) -> Torrent {
+    match Torrent::new(value) {
+        Ok(torrent) => {
+            if !torrent.info.is_canonical() {
+                eprintln!("Warning: info dictionary is not canonically encoded, re-encoding it would change the info hash");
+            }
+            torrent
+        },
+        Err(err) => {
+            eprintln!("Error: invalid torrent: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Splits a comma separated `-t` tier, exiting if it names no tracker at all.
+fn tracker_tier(urls: &str) -> Vec<String> {
+    let tier: Vec<String> = urls.split(',').map(str::trim).filter(|url| !url.is_empty()).map(String::from).collect();
+    if tier.is_empty() {
+        eprintln!("Error: -t needs at least one tracker URL");
+        process::exit(1);
+    }
+    tier
+}
+
+// Parses a magnet link, explaining what's wrong with it on failure.
+fn magnet_or_exit(magnet_link: &str) -> Magnet {
+    Magnet::new(magnet_link).unwrap_or_else(|err| {
+        eprintln!("Error: invalid magnet link: {}", err);
+        process::exit(1);
+    })
+}
+
+// Fetches the metadata of the torrent a magnet link points to, exiting if no peer sends it.
+fn torrent_from_magnet_or_exit(magnet: &Magnet) -> Torrent {
+    Torrent::from_magnet(magnet).unwrap_or_else(|| {
+        eprintln!("Error: no peer sent the metadata");
+        process::exit(1);
+    })
+}
+
+// Pieces are only fetched and checked by their v1 hashes, so v2-only torrents can't be downloaded.
+fn v1_or_exit(torrent: &Torrent) {
+    if !torrent.info.supports_v1() {
+        eprintln!("Error: downloading v2-only torrents isn't supported yet");
+        process::exit(1);
+    }
+}
+
+// Asks the torrent's trackers for peers, exiting with every tracker's failure if none answers.
+fn peers_or_exit(trackers: &mut TrackerList, info_hash: &[u8], peer_id: &str, left: usize) -> Vec<(String, u16)> {
+    trackers.get_peers(info_hash, peer_id, left).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    })
+}
+
+// Lays out the torrent's files under `output`, exiting if they can't be created.
+fn storage_or_exit(torrent: &Torrent, output: &str) -> Storage {
+    Storage::create(&torrent.info, Path::new(output)).unwrap_or_else(|err| {
+        eprintln!("Error: can't create {}: {}", output, err);
+        process::exit(1);
+    })
+}
+
+fn write_piece_or_exit(storage: &mut Storage, piece_index: usize, piece: &[u8], output: &str) {
+    storage.write_piece(piece_index, piece).unwrap_or_else(|err| {
+        eprintln!("Error: can't write piece {} to {}: {}", piece_index, output, err);
+        process::exit(1);
+    })
+}
+
+// Where `download` can get pieces from.
+enum PieceSource {
+    Peer(String),
+    WebSeed(WebSeed),
+    HttpSeed(HttpSeed),
+}
+
+impl PieceSource {
+    // The peer's address or the seed's URL.
+    fn address(&self) -> &str {
+        match self {
+            PieceSource::Peer(peer) => peer,
+            PieceSource::WebSeed(seed) => seed.url(),
+            PieceSource::HttpSeed(seed) => seed.url(),
+        }
+    }
+}
+
+// Tracker peers as host:port.
+fn peer_list(peers: &[(String, u16)]) -> Vec<String> {
+    peers.iter().map(|(ip, port)| format!("{}:{}", ip, port)).collect()
+}
+
+fn peer_sources(peers: &[(String, u16)]) -> Vec<PieceSource> {
+    peer_list(peers).into_iter().map(PieceSource::Peer).collect()
+}
+
+// Connects to `peer`, warning instead of failing so the caller can move on to the next one.
+fn try_connect(peer: &str) -> Option<(String, TcpStream)> {
+    match connect(peer) {
+        Ok(stream) => Some((peer.to_string(), stream)),
+        Err(err) => {
+            eprintln!("Warning: peer {}: {}", peer, err);
+            None
+        },
+    }
+}
+
+// Gathers the sources to download `torrent` from: web seeds, then `known_peers` and the trackers' peers.
+// Tracker failures only end the command when there's nothing else to download from.
+fn gather_sources(torrent: &mut Torrent, known_peers: &[String], my_id: &str, use_peers: bool, use_web_seeds: bool) -> Vec<PieceSource> {
+    let mut sources = vec![];
+    if use_web_seeds {
+        sources.extend(torrent.url_list().iter().map(|url| PieceSource::WebSeed(WebSeed::new(url))));
+        sources.extend(torrent.httpseeds().iter().map(|url| PieceSource::HttpSeed(HttpSeed::new(url))));
+    }
+    if use_peers {
+        sources.extend(known_peers.iter().map(|peer| PieceSource::Peer(peer.clone())));
+    }
+    if use_peers && sources.is_empty() {
+        let peers = peers_or_exit(&mut torrent.trackers, &torrent.info.get_info_hash_bytes(), my_id, torrent.info.get_file_size());
+        sources.extend(peer_sources(&peers));
+    } else if use_peers && !torrent.trackers.tiers().is_empty() {
+        match torrent.trackers.get_peers(&torrent.info.get_info_hash_bytes(), my_id, torrent.info.get_file_size()) {
+            Ok(peers) => sources.extend(peer_sources(&peers)),
+            Err(err) => eprintln!("Warning: {}", err),
+        }
+    }
+    if sources.is_empty() {
+        eprintln!("Error: no peers or web seeds to download from");
+        process::exit(1);
+    }
+    sources
+}
+
+// Pieces holding any byte of the files picked by a magnet link's `so`, or every piece without one.
+fn selected_pieces(torrent: &Torrent, select_only: &[RangeInclusive<usize>]) -> Vec<usize> {
+    if select_only.is_empty() {
+        return (0..torrent.info.total_pieces()).collect();
+    }
+    let piece_length = torrent.info.get_piece_size() as u64;
+    let mut pieces = BTreeSet::new();
+    for (index, file) in torrent.info.files().iter().enumerate() {
+        if file.length > 0 && select_only.iter().any(|range| range.contains(&index)) {
+            pieces.extend((file.offset / piece_length) as usize..=((file.offset + file.length - 1) / piece_length) as usize);
+        }
+    }
+    pieces.into_iter().collect()
+}
+
+// Fetches a piece from the first source that has it, starting at a different source for each piece to
+// spread the load. Sources that fail are skipped with a warning.
+fn download_piece_from<'a>(sources: &'a [PieceSource], torrent: &Torrent, my_id: &str, piece_index: usize) -> (&'a PieceSource, Vec<u8>) {
+    for i in 0..sources.len() {
+        let source = &sources[(piece_index + i) % sources.len()];
+        let result = match source {
+            PieceSource::Peer(peer) => download_piece(torrent, my_id, peer, piece_index).map_err(|err| ("peer", err.to_string())),
+            PieceSource::WebSeed(seed) => seed.download_piece(&torrent.info, piece_index).map_err(|err| ("web seed", err.to_string())),
+            PieceSource::HttpSeed(seed) => seed.download_piece(&torrent.info, piece_index).map_err(|err| ("HTTP seed", err.to_string())),
+        };
+        match result {
+            Ok(piece) => return (source, piece),
+            Err((kind, err)) => eprintln!("Warning: {} {}: {}", kind, source.address(), err),
+        }
+    }
+    eprintln!("Error: no source could provide piece {}", piece_index);
+    process::exit(1);
+}
+
+// Summarizes a finished `download` or `magnet_download` for `--json`.
+// `pieces` and `bytes` count what was actually fetched, which is less than the torrent for a selection.
+fn print_download_json(output: &str, torrent: &Torrent, pieces: usize, bytes: u64, seconds: f64) {
+    print_json(&serde_json::json!({
+        "output": output,
+        "info_hash": torrent.info.get_info_hash(),
+        "pieces": pieces,
+        "bytes": bytes,
+        "seconds": seconds,
+        "bytes_per_second": if seconds > 0.0 { bytes as f64 / seconds } else { 0.0 },
+    }));
+}
+
+// Prints a command's result for `--json`.
+fn print_json(value: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // --json is global: it can go anywhere and switches every command to JSON output. Arguments after
+    // a `--` are taken literally, so a positional `--json` can still be passed there.
+    let literal = match args.iter().position(|arg| arg == "--") {
+        Some(end) => args.split_off(end).split_off(1),
+        None => vec![],
+    };
+    let json = args.iter().any(|arg| arg == "--json");
+    args.retain(|arg| arg != "--json");
+    args.extend(literal);
     let command = &args[1];
 
     match command.as_str() {
         "decode" => {
-            let encoded_value = &args[2];
-            let (decoded_value, _) = decode_bencoded_value(encoded_value.as_bytes());
-            println!("{}", decoded_value);
+            // decode <bencoded value> | decode -f file.torrent | decode - (stdin)
+            let encoded_value = read_input(&args[2..]);
+            let decoded_value = decode_or_exit(&encoded_value).to_owned_value();
+            if json {
+                print_json(&value_to_json(&decoded_value));
+            } else {
+                println!("{}", decoded_value);
+            }
+        },
+        "encode" => {
+            // encode [-o out.torrent] <json> | encode -f file.json | encode - (stdin)
+            let mut output = None;
+            let mut input_args = vec![];
+            let mut args_iter = args[2..].iter();
+            while let Some(arg) = args_iter.next() {
+                if arg == "-o" {
+                    output = Some(args_iter.next().expect("Missing path after -o").clone());
+                } else {
+                    input_args.push(arg.clone());
+                }
+            }
+            let input = read_input(&input_args);
+            let value = json_str_to_value(&input).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            });
+            let encoded_value = encode_value(value);
+            match output {
+                Some(path) => {
+                    fs::write(&path, &encoded_value).unwrap();
+                    if json {
+                        print_json(&serde_json::json!({ "output": path, "bytes": encoded_value.len() }));
+                    }
+                },
+                None if json => {
+                    eprintln!("Error: --json needs -o, the encoded value would be written to stdout otherwise");
+                    process::exit(1);
+                },
+                None => io::stdout().write_all(&encoded_value).unwrap(),
+            }
+        },
+        "create" => {
+            // create [-o out.torrent] [-t url[,url...]]... [-w url]... [--piece-length bytes] [--comment text]
+            //        [--source text] [--private] [--skip-hidden] <file or directory>
+            let mut output = None;
+            let mut options = CreateOptions::default();
+            let mut paths = vec![];
+            let mut args_iter = args[2..].iter();
+            while let Some(arg) = args_iter.next() {
+                let mut value = || args_iter.next().unwrap_or_else(|| {
+                    eprintln!("Error: missing value after {}", arg);
+                    process::exit(1);
+                }).clone();
+                match arg.as_str() {
+                    "-o" => output = Some(value()),
+                    // each -t is a tier, trackers within a tier are separated by commas
+                    "-t" => options.trackers.push(tracker_tier(&value())),
+                    "-w" => options.web_seeds.push(value()),
+                    "--piece-length" => {
+                        let piece_length = value();
+                        options.piece_length = Some(piece_length.parse().unwrap_or_else(|_| {
+                            eprintln!("Error: invalid piece length {}", piece_length);
+                            process::exit(1);
+                        }));
+                    },
+                    "--comment" => options.comment = Some(value()),
+                    "--source" => options.source = Some(value()),
+                    "--private" => options.private = true,
+                    "--skip-hidden" => options.skip_hidden = true,
+                    _ => paths.push(arg.clone()),
+                }
+            }
+            let [path] = paths.as_slice() else {
+                eprintln!("Error: expected one file or directory to create a torrent from");
+                process::exit(1);
+            };
+            let metainfo = create_torrent(Path::new(path), &options).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            });
+            let encoded_value = encode_value(metainfo);
+            let output = output.unwrap_or_else(|| {
+                let name = Path::new(path).canonicalize().ok().and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
+                format!("{}.torrent", name.unwrap_or_else(|| "out".into()))
+            });
+            fs::write(&output, &encoded_value).unwrap_or_else(|err| {
+                eprintln!("Error: can't write {}: {}", output, err);
+                process::exit(1);
+            });
+            let (decoded_value, _) = decode_bencoded_ref(&encoded_value).expect("encode_value output always decodes");
+            let torrent = torrent_or_exit(&decoded_value);
+            if json {
+                print_json(&serde_json::json!({ "output": output, "info_hash": torrent.info.get_info_hash() }));
+            } else {
+                println!("Created {}", output);
+                println!("Info Hash: {}", torrent.info.get_info_hash());
+            }
+        },
+        "edit" => {
+            // edit [-o out.torrent] [--announce url] [-t url[,url...]]... [--remove-tracker url]...
+            //      [--replace-tracker old new]... [--comment text | --clear-comment]
+            //      [--created-by text | --clear-created-by] [-w url]... [--remove-web-seed url]...
+            //      [--clear-web-seeds] [--set path json]... [--delete path]... [--force] <file.torrent>
+            let mut output = None;
+            let mut edits = vec![];
+            let mut force = false;
+            let mut paths = vec![];
+            let mut args_iter = args[2..].iter();
+            while let Some(arg) = args_iter.next() {
+                let mut value = || args_iter.next().unwrap_or_else(|| {
+                    eprintln!("Error: missing value after {}", arg);
+                    process::exit(1);
+                }).clone();
+                match arg.as_str() {
+                    "-o" => output = Some(value()),
+                    "--announce" => edits.push(Edit::SetAnnounce(value())),
+                    "-t" => edits.push(Edit::AddTier(tracker_tier(&value()))),
+                    "--remove-tracker" => edits.push(Edit::RemoveTracker(value())),
+                    "--replace-tracker" => edits.push(Edit::ReplaceTracker { old: value(), new: value() }),
+                    "--comment" => edits.push(Edit::SetComment(Some(value()))),
+                    "--clear-comment" => edits.push(Edit::SetComment(None)),
+                    "--created-by" => edits.push(Edit::SetCreatedBy(Some(value()))),
+                    "--clear-created-by" => edits.push(Edit::SetCreatedBy(None)),
+                    "-w" => edits.push(Edit::AddWebSeed(value())),
+                    "--remove-web-seed" => edits.push(Edit::RemoveWebSeed(value())),
+                    "--clear-web-seeds" => edits.push(Edit::ClearWebSeeds),
+                    "--set" => {
+                        let path = value();
+                        let json = value();
+                        let value = json_str_to_value(json.as_bytes()).unwrap_or_else(|err| {
+                            eprintln!("Error: invalid JSON for {}: {}", path, err);
+                            process::exit(1);
+                        });
+                        edits.push(Edit::Set { path, value });
+                    },
+                    "--delete" => edits.push(Edit::Remove(value())),
+                    "--force" => force = true,
+                    _ => paths.push(arg.clone()),
+                }
+            }
+            let [path] = paths.as_slice() else {
+                eprintln!("Error: expected one torrent file to edit");
+                process::exit(1);
+            };
+            let encoded_value = fs::read(path).unwrap_or_else(|err| {
+                eprintln!("Error: can't read {}: {}", path, err);
+                process::exit(1);
+            });
+            let edited_value = edit_torrent(&encoded_value, &edits, force).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            });
+            // make sure the result is still a torrent before overwriting anything
+            let old_torrent = torrent_or_exit(&decode_or_exit(&encoded_value));
+            let new_torrent = torrent_or_exit(&decode_or_exit(&edited_value));
+            let output = output.unwrap_or_else(|| path.clone());
+            fs::write(&output, &edited_value).unwrap_or_else(|err| {
+                eprintln!("Error: can't write {}: {}", output, err);
+                process::exit(1);
+            });
+            let (old_hash, new_hash) = (old_torrent.info.get_info_hash(), new_torrent.info.get_info_hash());
+            if json {
+                print_json(&serde_json::json!({ "output": output, "old_info_hash": old_hash, "info_hash": new_hash, "info_hash_changed": old_hash != new_hash }));
+            } else if old_hash == new_hash {
+                println!("Info hash unchanged: {}", new_hash);
+            } else {
+                println!("Info hash changed: {} -> {}", old_hash, new_hash);
+            }
+        },
+        "query" => {
+            // query [--format json|bencode|raw] <file.torrent | -> <path>
+            // --json pretty-prints the JSON format, so it can't be combined with the other formats
+            let mut format = String::from("json");
+            let mut positional = vec![];
+            let mut args_iter = args[2..].iter();
+            while let Some(arg) = args_iter.next() {
+                if arg == "--format" {
+                    format = args_iter.next().expect("Missing value after --format").clone();
+                } else {
+                    positional.push(arg.clone());
+                }
+            }
+            let [filename, path] = positional.as_slice() else {
+                eprintln!("Error: expected a file and a path");
+                process::exit(1);
+            };
+            if json && format != "json" {
+                eprintln!("Error: --json can't be combined with --format {}", format);
+                process::exit(1);
+            }
+            let input_args = if filename == "-" { vec![filename.clone()] } else { vec!["-f".into(), filename.clone()] };
+            let contents = read_input(&input_args);
+            let document = decode_or_exit(&contents).to_owned_value();
+            let value = document.pointer(path).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            });
+            match (format.as_str(), value) {
+                ("json", value) if json => print_json(&value_to_json(value)),
+                ("json", value) => println!("{}", value),
+                ("bencode", value) => io::stdout().write_all(&encode_value(value.clone())).unwrap(),
+                ("raw", Value::String(bytes)) => io::stdout().write_all(bytes).unwrap(),
+                ("raw", Value::Int(int)) => println!("{}", int),
+                ("raw", Value::BigInt(int)) => println!("{}", int),
+                ("raw", _) => {
+                    eprintln!("Error: raw output needs a string or an integer, use --format json or bencode");
+                    process::exit(1);
+                },
+                (other, _) => {
+                    eprintln!("Error: unknown format {}, expected json, bencode or raw", other);
+                    process::exit(1);
+                },
+            }
         },
         "info" => {
-            let filename = &args[2];
+            // info [--strict] sample.torrent
+            let strict = args[2..].iter().any(|arg| arg == "--strict");
+            let filename = args[2..].iter().find(|arg| *arg != "--strict").expect("Missing torrent file for info");
             let contents = fs::read(filename).unwrap();
-            let (decoded_value, _) = decode_bencoded_value(&contents);
-            let torrent = Torrent::new(decoded_value).unwrap();
-            torrent.print_info();
+            let decoded_value = if strict {
+                decode_strict_or_exit(&contents)
+            } else {
+                decode_or_exit(&contents)
+            };
+            let torrent = torrent_or_exit(&decoded_value);
+            if json {
+                print_json(&torrent.info_json());
+            } else {
+                torrent.print_info();
+            }
+        },
+        "validate" => {
+            // validate [--format text|json] <file.torrent>, exits with 1 when there are errors
+            let mut format = String::from(if json { "json" } else { "text" });
+            let mut paths = vec![];
+            let mut args_iter = args[2..].iter();
+            while let Some(arg) = args_iter.next() {
+                if arg == "--format" {
+                    format = args_iter.next().expect("Missing value after --format").clone();
+                } else {
+                    paths.push(arg.clone());
+                }
+            }
+            let [path] = paths.as_slice() else {
+                eprintln!("Error: expected one torrent file to validate");
+                process::exit(1);
+            };
+            let contents = read_input(&["-f".into(), path.clone()]);
+            let findings = validate_torrent(&contents).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            });
+            let errors = findings.iter().filter(|finding| finding.severity == Severity::Error).count();
+            let warnings = findings.len() - errors;
+            match format.as_str() {
+                "text" => {
+                    for finding in &findings {
+                        println!("{}", finding);
+                    }
+                    println!("{} error(s), {} warning(s)", errors, warnings);
+                },
+                "json" => {
+                    let report = serde_json::json!({ "valid": errors == 0, "errors": errors, "warnings": warnings, "findings": findings });
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                },
+                other => {
+                    eprintln!("Error: unknown format {}, expected text or json", other);
+                    process::exit(1);
+                },
+            }
+            if errors > 0 {
+                process::exit(1);
+            }
+        },
+        "diff" => {
+            // diff old.torrent new.torrent
+            let [old_file, new_file] = &args[2..] else {
+                eprintln!("Error: expected two torrent files");
+                process::exit(1);
+            };
+            let old_contents = read_input(&["-f".into(), old_file.clone()]);
+            let new_contents = read_input(&["-f".into(), new_file.clone()]);
+            let (old_value, _) = decode_bencoded_value(&old_contents).unwrap_or_else(|err| {
+                eprintln!("Error: {}: {}", old_file, err);
+                process::exit(1);
+            });
+            let (new_value, _) = decode_bencoded_value(&new_contents).unwrap_or_else(|err| {
+                eprintln!("Error: {}: {}", new_file, err);
+                process::exit(1);
+            });
+            let differences = diff_values(&old_value, &new_value);
+            // the hash is taken over the info bytes as they appear in each file, not over the decoded values
+            let raw_info = |contents| match decode_or_exit(contents) {
+                ValueRef::Map(map) => map.get_raw("info").map(info_hash_hex),
+                _ => None,
+            };
+            let (old_hash, new_hash) = (raw_info(&old_contents), raw_info(&new_contents));
+            if json {
+                print_json(&serde_json::json!({
+                    "differences": differences.iter().map(|difference| difference.to_json()).collect::<Vec<_>>(),
+                    "old_info_hash": old_hash,
+                    "new_info_hash": new_hash,
+                }));
+                return;
+            }
+            for difference in &differences {
+                println!("{}", difference);
+            }
+            if differences.is_empty() {
+                println!("No differences");
+            }
+
+            match (old_hash, new_hash) {
+                (Some(old_hash), Some(new_hash)) => {
+                    if old_hash == new_hash {
+                        println!("Info hash: identical ({})", old_hash);
+                    } else {
+                        println!("Info hash: changed ({} -> {})", old_hash, new_hash);
+                        if !differences.iter().any(|difference| difference.path.first() == Some(&PathSegment::Key(b"info".to_vec()))) {
+                            println!("The info dictionaries decode identically, only their encoding differs");
+                        }
+                    }
+                },
+                (old_info, new_info) => {
+                    for (file, info) in [(old_file, old_info), (new_file, new_info)] {
+                        if info.is_none() {
+                            println!("Info hash: {} has no info dictionary", file);
+                        }
+                    }
+                },
+            }
         },
         "peers" => {
             let filename = &args[2];
             let contents = fs::read(filename).unwrap();
-            let (decoded_value, _) = decode_bencoded_value(&contents);
-            let torrent = Torrent::new(decoded_value).unwrap();
+            let decoded_value = decode_or_exit(&contents);
+            let mut torrent = torrent_or_exit(&decoded_value);
             
             let peer_id = generate_random_string(20);
-            let peers = get_peers(&torrent.get_url(), &torrent.info.get_info_hash_bytes(), &peer_id, torrent.info.get_file_size());
-            for peer in peers {
-                println!("{}:{}", peer.0, peer.1)
+            let peers = peers_or_exit(&mut torrent.trackers, &torrent.info.get_info_hash_bytes(), &peer_id, torrent.info.get_file_size());
+            if json {
+                let peers: Vec<_> = peers.iter().map(|(ip, port)| serde_json::json!({ "ip": ip, "port": port })).collect();
+                print_json(&serde_json::json!({ "peers": peers }));
+            } else {
+                for peer in peers {
+                    println!("{}:{}", peer.0, peer.1)
+                }
             }
 
         },
         "handshake" => {
             let filename = &args[2];
             let contents = fs::read(filename).unwrap();
-            let (decoded_value, _) = decode_bencoded_value(&contents);
-            let torrent = Torrent::new(decoded_value).unwrap();
+            let decoded_value = decode_or_exit(&contents);
+            let torrent = torrent_or_exit(&decoded_value);
             let peer = &args[3];
 
             let self_id = generate_random_string(20);
@@ -65,12 +639,17 @@ fn main() {
             stream.write_all(&handshake).expect("Failed to write to stream");
 
             let mut buffer = [0; 1024];
-            stream.read(&mut buffer).expect("Failed to read from stream");
+            stream.read_exact(&mut buffer[0..1]).expect("Failed to read from stream");
             let protocol_length = buffer[0] as usize;
+            stream.read_exact(&mut buffer[1..1+protocol_length+8+20+20]).expect("Failed to read from stream");
             let start = 1 + protocol_length + 8 + 20;
             let peer_id = buffer[start..start+20].to_vec();
             let peer_id = hex::encode(peer_id);
-            println!("Peer ID: {}", peer_id);
+            if json {
+                print_json(&serde_json::json!({ "peer": peer, "peer_id": peer_id }));
+            } else {
+                println!("Peer ID: {}", peer_id);
+            }
         },
         "download_piece" => {
             //  -o /tmp/test-piece sample.torrent <piece_index>
@@ -79,8 +658,7 @@ fn main() {
             let mut storage_location = String::new();
             let mut filename = String::new();
             let mut piece_index = 0;
-            for i in 2..args.len() {
-                let arg = &args[i];
+            for arg in &args[2..] {
                 if set_storage_location {
                     set_storage_location = false;
                     storage_location = arg.into();
@@ -95,7 +673,7 @@ fn main() {
                     filename = arg.into();
                     values_set.1 = true;
                 } else if !values_set.2 {
-                    piece_index = usize::from_str_radix(arg, 10).unwrap();
+                    piece_index = arg.parse().unwrap();
                     values_set.2 = true;
                 } else {
                     panic!("Unexpected parameter for download_piece");
@@ -105,24 +683,34 @@ fn main() {
                 panic!("Missing parameters for download_piece")
             }
             let content = fs::read(filename).unwrap();
-            let (decoded_value, _) = decode_bencoded_value(&content);
-            let torrent = Torrent::new(decoded_value).unwrap();
+            let decoded_value = decode_or_exit(&content);
+            let mut torrent = torrent_or_exit(&decoded_value);
+            v1_or_exit(&torrent);
             let my_id = generate_random_string(20);
-            let peers = get_peers(&torrent.get_url(), &torrent.info.get_info_hash_bytes(), &my_id, torrent.info.get_file_size());
-            let peer = format!("{}:{}", peers[0].0, peers[0].1);
-            let piece = download_piece(&torrent, &my_id, &peer, piece_index);
-            let mut file = File::create(storage_location).unwrap();
+            let peers = peers_or_exit(&mut torrent.trackers, &torrent.info.get_info_hash_bytes(), &my_id, torrent.info.get_file_size());
+            let sources = peer_sources(&peers);
+            let started = Instant::now();
+            let (source, piece) = download_piece_from(&sources, &torrent, &my_id, piece_index);
+            let seconds = started.elapsed().as_secs_f64();
+            let mut file = File::create(&storage_location).unwrap();
             file.write_all(&piece).unwrap();
-            println!("Piece downloaded.");
+            if json {
+                print_json(&serde_json::json!({
+                    "output": storage_location, "piece": piece_index, "peer": source.address(), "bytes": piece.len(), "seconds": seconds,
+                }));
+            } else {
+                println!("Piece downloaded.");
+            }
         },
         "download" => {
-            // download -o /tmp/test.txt sample.torrent
+            // download -o /tmp/test.txt sample.torrent, or -o <directory> for a multi-file torrent
+            // --web-seeds-only skips the trackers, --no-web-seeds ignores the torrent's url-list and httpseeds
             let mut set_storage_location = false;
             let mut storage_location = String::new();
             let mut filename = String::new();
             let mut values_set = (false, false);
-            for i in 2..args.len() {
-                let arg = &args[i];
+            let (mut use_peers, mut use_web_seeds) = (true, true);
+            for arg in &args[2..] {
                 if set_storage_location {
                     set_storage_location = false;
                     storage_location = arg.into();
@@ -133,6 +721,14 @@ fn main() {
                     set_storage_location = true;
                     continue;
                 }
+                if arg == "--web-seeds-only" {
+                    use_peers = false;
+                    continue;
+                }
+                if arg == "--no-web-seeds" {
+                    use_web_seeds = false;
+                    continue;
+                }
                 if !values_set.1 {
                     filename = arg.into();
                     values_set.1 = true;
@@ -144,35 +740,54 @@ fn main() {
                 panic!("Missing parameters for download")
             }
             let content = fs::read(filename).unwrap();
-            let (decoded_value, _) = decode_bencoded_value(&content);
-            let torrent = Torrent::new(decoded_value).unwrap();
+            let decoded_value = decode_or_exit(&content);
+            let mut torrent = torrent_or_exit(&decoded_value);
+            v1_or_exit(&torrent);
             let my_id = generate_random_string(20);
-            let peers = get_peers(&torrent.get_url(), &torrent.info.get_info_hash_bytes(), &my_id, torrent.info.get_file_size());
+            let sources = gather_sources(&mut torrent, &[], &my_id, use_peers, use_web_seeds);
             let piece_num = torrent.info.total_pieces();
-            let mut file_contents = vec![];
+            let mut storage = storage_or_exit(&torrent, &storage_location);
+            let started = Instant::now();
+            let mut bytes = 0;
             for i in 0..piece_num {
-                let peer = format!("{}:{}", peers[i%peers.len()].0, peers[i%peers.len()].1);
-                file_contents.extend(download_piece(&torrent, &my_id, &peer, i));
+                let piece = download_piece_from(&sources, &torrent, &my_id, i).1;
+                bytes += piece.len() as u64;
+                write_piece_or_exit(&mut storage, i, &piece, &storage_location);
+            }
+            if json {
+                print_download_json(&storage_location, &torrent, piece_num, bytes, started.elapsed().as_secs_f64());
+            } else {
+                println!("File downloaded.")
             }
-            let mut file = File::create(storage_location).unwrap();
-            file.write_all(&file_contents).unwrap();
-            println!("File downloaded.")
         },
         "magnet_parse" => {
             let magnet_link = &args[2];
-            let magnet = Magnet::new(magnet_link).unwrap();
-            magnet.print_info();
+            let magnet = magnet_or_exit(magnet_link);
+            if json {
+                print_json(&magnet.info_json());
+            } else {
+                magnet.print_info();
+            }
         },
         "magnet_handshake" => {
             let magnet_link = &args[2];
-            let magnet = Magnet::new(magnet_link).unwrap();
+            let magnet = magnet_or_exit(magnet_link);
             let my_id = generate_random_string(20);
             let info_hash = magnet.get_info_hash_bytes();
             let handshake = get_handshake(&info_hash, &my_id, true);
-            let peers = get_peers(&magnet.get_url().unwrap(), &info_hash, &my_id, 999);
-            let peer = format!("{}:{}", peers[0].0, peers[0].1);
-
-            let mut stream = TcpStream::connect(peer).expect("Failed to connect");
+            // the link's own peers come first, the trackers are only asked when none of those answers
+            let mut peers = magnet.peers().to_vec();
+            let connected = peers.iter().find_map(|peer| try_connect(peer));
+            let (peer, mut stream) = match connected {
+                Some(connected) => connected,
+                None => {
+                    peers = peer_list(&peers_or_exit(&mut magnet.tracker_list(), &info_hash, &my_id, 999));
+                    peers.iter().find_map(|peer| try_connect(peer)).unwrap_or_else(|| {
+                        eprintln!("Error: couldn't connect to any peer");
+                        process::exit(1);
+                    })
+                },
+            };
             stream.write_all(&handshake).expect("Failed to write to stream");
 
             let mut buffer = [0; 1024];
@@ -184,23 +799,19 @@ fn main() {
             let peer_id = buffer[start..start+20].to_vec();
             let peer_id = hex::encode(peer_id);
 
-            // wait for bitfield
-            stream.read_exact(&mut buffer[..4]).expect("Failed to read from stream");
+            // wait for bitfield, its contents don't matter here
+            stream.read_exact(&mut buffer[..5]).expect("Failed to read from stream");
             let length = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-            stream.read_exact(&mut buffer[4..4+length as usize]).unwrap();
             let message_type = buffer[4];
-            if message_type != 5 {
+            if length == 0 || message_type != 5 {
                 panic!("Didn't get bitfield message {}", message_type);
             }
-            
-            println!("Peer ID: {}", peer_id);
+            io::copy(&mut (&mut stream).take(length as u64 - 1), &mut io::sink()).expect("Failed to read from stream");
+
+            let mut metadata_ext_id = None;
             //extension handshake
             if has_extension_support {
-                let mut inner_dict = Map::new();
-                inner_dict.insert("ut_metadata".as_bytes().to_vec(), Value::Int(1));
-                let mut outer_dict = Map::new();
-                outer_dict.insert("m".as_bytes().to_vec(), Value::Map(inner_dict));
-                let bencoded_value = encode_value(Value::Map(outer_dict));
+                let bencoded_value = encode_value(bencode!({ "m": { "ut_metadata": 1 } }));
                 let mut extension_handshake = vec![];
                 extension_handshake.extend((bencoded_value.len() as u32 + 2).to_be_bytes());
                 extension_handshake.push(20);
@@ -208,19 +819,38 @@ fn main() {
                 extension_handshake.extend(bencoded_value);
                 stream.write_all(&extension_handshake).expect("Couldn't write to stream");
 
-                stream.read_exact(&mut buffer[0..4]).expect("Couldn't read from stream");
+                stream.read_exact(&mut buffer[0..6]).expect("Couldn't read from stream");
                 let length = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-                stream.read_exact(&mut buffer[4..4+length as usize]).expect("Failed to read from stream");
                 let message_type = buffer[4];
                 if message_type != 20 {
                     panic!("Didn't get extension handshake type {}", message_type)
                 }
-                let bencoded_dict = &buffer[6..4+length as usize];
-                let outer_dict = decode_bencoded_value(bencoded_dict).0.get_map().unwrap();
-                let inner_dict = outer_dict.get("m").unwrap().get_map().unwrap();
-                let result = inner_dict.get("ut_metadata");
-                if let Some(metadata_ext_id) = result {
-                    let metadata_ext_id  = metadata_ext_id.get_int().unwrap();
+                if length < 2 || length as usize > Limits::network().max_alloc {
+                    eprintln!("Error: invalid extension handshake length: {}", length);
+                    process::exit(1);
+                }
+                // decode the handshake dictionary straight off the socket, then skip anything after it
+                let mut decoder = StreamDecoder::with_limits(BufReader::new((&mut stream).take(length as u64 - 2)), Limits::network());
+                let extension_handshake = match decoder.read_value() {
+                    Ok(Some(value)) => from_value::<ExtensionHandshake>(value),
+                    Ok(None) => panic!("Empty extension handshake"),
+                    Err(err) => {
+                        eprintln!("Error: {}", err);
+                        process::exit(1);
+                    }
+                };
+                let extension_handshake = extension_handshake.unwrap_or_else(|err| {
+                    eprintln!("Error: invalid extension handshake: {}", err);
+                    process::exit(1);
+                });
+                io::copy(&mut decoder.into_inner(), &mut io::sink()).expect("Couldn't read from stream");
+                metadata_ext_id = extension_handshake.ut_metadata();
+            }
+            if json {
+                print_json(&serde_json::json!({ "peer": peer, "peer_id": peer_id, "metadata_extension_id": metadata_ext_id }));
+            } else {
+                println!("Peer ID: {}", peer_id);
+                if let Some(metadata_ext_id) = metadata_ext_id {
                     println!("Peer Metadata Extension ID: {}", metadata_ext_id);
                 }
             }
@@ -228,9 +858,13 @@ fn main() {
         },
         "magnet_info" => {
             let magnet_link = &args[2];
-            let magnet = Magnet::new(magnet_link).unwrap();
-            let torrent = Torrent::from_magnet(magnet).unwrap();
-            torrent.print_info();
+            let magnet = magnet_or_exit(magnet_link);
+            let torrent = torrent_from_magnet_or_exit(&magnet);
+            if json {
+                print_json(&torrent.info_json());
+            } else {
+                torrent.print_info();
+            }
         },
         "magnet_download_piece" => {
             // magnet_download_piece -o /tmp/test-piece-0 <magnet-link> 0
@@ -239,8 +873,7 @@ fn main() {
             let mut storage_location = String::new();
             let mut magnet_link = String::new();
             let mut piece_index = 0;
-            for i in 2..args.len() {
-                let arg = &args[i];
+            for arg in &args[2..] {
                 if set_storage_location {
                     set_storage_location = false;
                     storage_location = arg.into();
@@ -255,7 +888,7 @@ fn main() {
                     magnet_link = arg.into();
                     values_set.1 = true;
                 } else if !values_set.2 {
-                    piece_index = usize::from_str_radix(arg, 10).unwrap();
+                    piece_index = arg.parse().unwrap();
                     values_set.2 = true;
                 } else {
                     panic!("Unexpected parameter for download_piece");
@@ -265,23 +898,30 @@ fn main() {
                 panic!("Missing parameters for download_piece")
             }
 
-            let magnet = Magnet::new(&magnet_link).unwrap();
-            let torrent = Torrent::from_magnet(magnet).unwrap();
+            let magnet = magnet_or_exit(&magnet_link);
+            let mut torrent = torrent_from_magnet_or_exit(&magnet);
+            v1_or_exit(&torrent);
             let my_id = generate_random_string(20);
-            let peers = get_peers(&torrent.get_url(), &torrent.info.get_info_hash_bytes(), &my_id, torrent.info.get_file_size());
-            let peer = format!("{}:{}", peers[0].0, peers[0].1);
-            let piece = download_piece(&torrent, &my_id, &peer, piece_index);
-            let mut file = File::create(storage_location).unwrap();
+            let sources = gather_sources(&mut torrent, magnet.peers(), &my_id, true, false);
+            let started = Instant::now();
+            let (source, piece) = download_piece_from(&sources, &torrent, &my_id, piece_index);
+            let seconds = started.elapsed().as_secs_f64();
+            let mut file = File::create(&storage_location).unwrap();
             file.write_all(&piece).unwrap();
-            println!("Piece downloaded.");
+            if json {
+                print_json(&serde_json::json!({
+                    "output": storage_location, "piece": piece_index, "peer": source.address(), "bytes": piece.len(), "seconds": seconds,
+                }));
+            } else {
+                println!("Piece downloaded.");
+            }
         },
         "magnet_download" => {
             let mut set_storage_location = false;
             let mut storage_location = String::new();
             let mut magnet_link = String::new();
             let mut values_set = (false, false);
-            for i in 2..args.len() {
-                let arg = &args[i];
+            for arg in &args[2..] {
                 if set_storage_location {
                     set_storage_location = false;
                     storage_location = arg.into();
@@ -302,19 +942,26 @@ fn main() {
             if values_set != (true, true) {
                 panic!("Missing parameters for download")
             }
-            let magnet = Magnet::new(&magnet_link).unwrap();
-            let torrent = Torrent::from_magnet(magnet).unwrap();
+            let magnet = magnet_or_exit(&magnet_link);
+            let mut torrent = torrent_from_magnet_or_exit(&magnet);
+            v1_or_exit(&torrent);
             let my_id = generate_random_string(20);
-            let peers = get_peers(&torrent.get_url(), &torrent.info.get_info_hash_bytes(), &my_id, torrent.info.get_file_size());
-            let piece_num = torrent.info.total_pieces();
-            let mut file_contents = vec![];
-            for i in 0..piece_num {
-                let peer = format!("{}:{}", peers[i%peers.len()].0, peers[i%peers.len()].1);
-                file_contents.extend(download_piece(&torrent, &my_id, &peer, i));
+            let sources = gather_sources(&mut torrent, magnet.peers(), &my_id, true, true);
+            // files outside the selection are still created, but only the pieces overlapping selected files are fetched
+            let pieces = selected_pieces(&torrent, magnet.select_only());
+            let mut storage = storage_or_exit(&torrent, &storage_location);
+            let started = Instant::now();
+            let mut bytes = 0;
+            for &i in &pieces {
+                let piece = download_piece_from(&sources, &torrent, &my_id, i).1;
+                bytes += piece.len() as u64;
+                write_piece_or_exit(&mut storage, i, &piece, &storage_location);
+            }
+            if json {
+                print_download_json(&storage_location, &torrent, pieces.len(), bytes, started.elapsed().as_secs_f64());
+            } else {
+                println!("File downloaded.")
             }
-            let mut file = File::create(storage_location).unwrap();
-            file.write_all(&file_contents).unwrap();
-            println!("File downloaded.")
         },
         _ => {
             println!("unknown command: {}", args[1])
@@ -1,4 +1,16 @@
 pub mod value;
 pub mod bencode;
+pub mod stream;
+pub mod value_serde;
 pub mod torrent;
-pub mod helpers;
\ No newline at end of file
+pub mod helpers;
+pub mod json;
+pub mod diff;
+pub mod storage;
+pub mod tracker;
+pub mod v2;
+pub mod create;
+pub mod edit;
+pub mod validate;
+pub mod webseed;
+pub mod magnet;
@@ -1,86 +1,520 @@
-use crate::modules::value::{Map, Value};
+use std::fmt;
 
-fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+use thiserror::Error;
+
+use crate::modules::{stream::write_value, value::{parse_int, MapRef, Value, ValueRef}};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("unexpected end of input at byte {offset}, expected {expected}")]
+    UnexpectedEof { offset: usize, expected: &'static str },
+    #[error("unexpected byte {} at byte {offset}, expected {expected}", describe_byte(*.found))]
+    UnexpectedByte { offset: usize, found: u8, expected: &'static str },
+    #[error("invalid string length at byte {offset}")]
+    InvalidLength { offset: usize },
+    #[error("invalid integer at byte {offset}")]
+    InvalidInteger { offset: usize },
+    #[error("dictionary key at byte {offset} is not a string")]
+    NonStringKey { offset: usize },
+    #[error("{limit} limit exceeded at byte {offset}")]
+    LimitExceeded { offset: usize, limit: &'static str },
+}
+
+/// Caps on how much work a decoder will do for a single document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Deepest allowed nesting of lists and dictionaries.
+    pub max_depth: usize,
+    /// Total bytes the decoded value may occupy, counting string contents and per-element overhead.
+    pub max_alloc: usize,
+    pub max_string_len: usize,
+    /// Total number of values, at any depth.
+    pub max_elements: usize,
 }
 
-fn find_index(vector: &[u8], c: char) -> usize {
-    vector.iter().position(|u| *u == c as u8).unwrap()
+impl Limits {
+    /// Limits for input received from trackers and peers, which may be hostile.
+    pub const fn network() -> Self {
+        Self { max_depth: 32, max_alloc: 64 * 1024 * 1024, max_string_len: 16 * 1024 * 1024, max_elements: 1_000_000 }
+    }
+}
+
+impl Default for Limits {
+    /// Only bounds nesting, so that a local file can't overflow the stack of the recursive decoders.
+    fn default() -> Self {
+        Self { max_depth: 512, max_alloc: usize::MAX, max_string_len: usize::MAX, max_elements: usize::MAX }
+    }
+}
+
+/// Tracks a decoder's usage against its [`Limits`].
+pub(crate) struct Budget {
+    limits: Limits,
+    depth: usize,
+    elements: usize,
+    allocated: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self { limits, depth: 0, elements: 0, allocated: 0 }
+    }
+
+    fn allocate(&mut self, offset: usize, bytes: usize) -> Result<(), DecodeError> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        if self.allocated > self.limits.max_alloc {
+            return Err(DecodeError::LimitExceeded { offset, limit: "allocation" });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn element(&mut self, offset: usize) -> Result<(), DecodeError> {
+        self.elements += 1;
+        if self.elements > self.limits.max_elements {
+            return Err(DecodeError::LimitExceeded { offset, limit: "element count" });
+        }
+        self.allocate(offset, std::mem::size_of::<Value>())
+    }
+
+    pub(crate) fn string(&mut self, offset: usize, length: usize) -> Result<(), DecodeError> {
+        if length > self.limits.max_string_len {
+            return Err(DecodeError::LimitExceeded { offset, limit: "string length" });
+        }
+        self.allocate(offset, length)
+    }
+
+    /// Big integers are kept as their digits, so their length is bounded like a string's.
+    pub(crate) fn integer_digits(&self, offset: usize, digits: usize) -> Result<(), DecodeError> {
+        if digits > self.limits.max_string_len {
+            return Err(DecodeError::LimitExceeded { offset, limit: "integer length" });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn max_integer_digits(&self) -> usize {
+        self.limits.max_string_len
+    }
+
+    pub(crate) fn enter(&mut self, offset: usize) -> Result<(), DecodeError> {
+        self.depth += 1;
+        if self.depth > self.limits.max_depth {
+            return Err(DecodeError::LimitExceeded { offset, limit: "nesting depth" });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.depth -= 1;
+    }
+}
+
+/// A canonical-encoding rule broken by an otherwise well-formed document, found by [`decode_strict`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ViolationKind {
+    #[error("dictionary key {0:?} is not in sorted order")]
+    UnsortedKey(String),
+    #[error("dictionary key {0:?} appears more than once")]
+    DuplicateKey(String),
+    #[error("integer is negative zero")]
+    NegativeZero,
+    #[error("integer has leading zeros")]
+    LeadingZeroInteger,
+    #[error("string length has leading zeros")]
+    LeadingZeroLength,
+    #[error("{0} bytes of trailing data after the top-level value")]
+    TrailingData(usize),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub path: String,
+    pub offset: usize,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "<root>" } else { &self.path };
+        write!(f, "{} (byte {}): {}", path, self.offset, self.kind)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StrictError {
+    #[error(transparent)]
+    Malformed(#[from] DecodeError),
+    #[error("{} canonical encoding violation(s)", .0.len())]
+    NonCanonical(Vec<Violation>),
+}
+
+fn describe_byte(byte: u8) -> String {
+    if byte.is_ascii_graphic() {
+        format!("'{}'", byte as char)
+    } else {
+        format!("0x{:02x}", byte)
+    }
+}
+
+fn find_index(vector: &[u8], c: u8) -> Option<usize> {
+    vector.iter().position(|u| *u == c)
 }
 
 pub fn encode_value(value: Value) -> Vec<u8> {
-    match value {
-        Value::Int(val) => format!("i{}e", val.to_string()).as_bytes().to_vec(),
-        Value::String(val) => {
-            let mut bencoded = vec![];
-            bencoded.extend(format!("{}:", val.len()).as_bytes());
-            bencoded.extend(val);
-            bencoded
-        },
-        Value::List(list) => {
-            let mut bencoded = vec![];
-            bencoded.push('l' as u8);
-            for value in list {
-                bencoded.extend(encode_value(value));
+    let mut bencoded = vec![];
+    write_value(&value, &mut bencoded).expect("Writing to a Vec can't fail");
+    bencoded
+}
+
+/// Decodes the first value in `encoded_value` into an owned [`Value`], returning it with the unconsumed input.
+pub fn decode_bencoded_value(encoded_value: &[u8]) -> Result<(Value, &[u8]), DecodeError> {
+    let (value, rest) = decode_bencoded_ref(encoded_value)?;
+    Ok((value.to_owned_value(), rest))
+}
+
+/// Like [`decode_bencoded_value`], but refuses input that goes over `limits`.
+/// Anything received from the network should be decoded through this.
+pub fn decode_with_limits(encoded_value: &[u8], limits: Limits) -> Result<(Value, &[u8]), DecodeError> {
+    let mut decoder = Decoder::new(encoded_value, false, limits);
+    let value = decoder.decode_value()?;
+    Ok((value.to_owned_value(), &encoded_value[decoder.pos..]))
+}
+
+/// Decodes the first value in `encoded_value` without copying byte strings out of it.
+pub fn decode_bencoded_ref(encoded_value: &[u8]) -> Result<(ValueRef<'_>, &[u8]), DecodeError> {
+    let mut decoder = Decoder::new(encoded_value, false, Limits::default());
+    let value = decoder.decode_value()?;
+    Ok((value, &encoded_value[decoder.pos..]))
+}
+
+/// Decodes a complete document, rejecting any encoding that isn't canonical bencode: unsorted or duplicate
+/// dictionary keys, `i-0e`, leading zeros in integers or string lengths, and trailing data.
+/// Every violation is reported along with its path in the document.
+pub fn decode_strict(encoded_value: &[u8]) -> Result<ValueRef<'_>, StrictError> {
+    let mut decoder = Decoder::new(encoded_value, true, Limits::default());
+    let value = decoder.decode_value()?;
+    if decoder.pos < encoded_value.len() {
+        decoder.violation(decoder.pos, ViolationKind::TrailingData(encoded_value.len() - decoder.pos));
+    }
+    if !decoder.violations.is_empty() {
+        return Err(StrictError::NonCanonical(decoder.violations));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    strict: bool,
+    // Only tracked in strict mode, to label violations.
+    path: Vec<String>,
+    violations: Vec<Violation>,
+    budget: Budget,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8], strict: bool, limits: Limits) -> Self {
+        Self { input, pos: 0, strict, path: vec![], violations: vec![], budget: Budget::new(limits) }
+    }
+
+    fn violation(&mut self, offset: usize, kind: ViolationKind) {
+        let mut path = String::new();
+        for segment in &self.path {
+            if !path.is_empty() && !segment.starts_with('[') {
+                path.push('.');
             }
-            bencoded.push('e' as u8);
-            bencoded
-        },
-        Value::Map(map) => {
-            let mut bencoded = vec![];
-            bencoded.push('d' as u8);
-            for (key, val) in map {
-                bencoded.extend(encode_value(Value::String(key)));
-                bencoded.extend(encode_value(val));
+            path.push_str(segment);
+        }
+        self.violations.push(Violation { path, offset, kind });
+    }
+
+    fn peek(&self, expected: &'static str) -> Result<u8, DecodeError> {
+        self.input.get(self.pos).copied().ok_or(DecodeError::UnexpectedEof { offset: self.pos, expected })
+    }
+
+    fn decode_value(&mut self) -> Result<ValueRef<'a>, DecodeError> {
+        let first = self.peek("a value")?;
+        self.budget.element(self.pos)?;
+        match first {
+            b'0'..=b'9' => Ok(ValueRef::String(self.decode_string()?)),
+            b'i' => self.decode_int(),
+            b'l' => {
+                // Example: "l5:helloi52ee" -> ["hello",52]
+                self.budget.enter(self.pos)?;
+                self.pos += 1;
+                let mut value_list = vec![];
+                while self.peek("a list element or 'e'")? != b'e' {
+                    if self.strict {
+                        self.path.push(format!("[{}]", value_list.len()));
+                    }
+                    value_list.push(self.decode_value()?);
+                    if self.strict {
+                        self.path.pop();
+                    }
+                }
+                self.pos += 1;
+                self.budget.leave();
+                Ok(ValueRef::List(value_list))
+            },
+            b'd' => {
+                // Example: d3:foo3:bar5:helloi52ee
+                self.budget.enter(self.pos)?;
+                self.pos += 1;
+                let mut map = MapRef::new();
+                let mut previous_key: Option<&[u8]> = None;
+                while self.peek("a dictionary key or 'e'")? != b'e' {
+                    if !self.input[self.pos].is_ascii_digit() {
+                        return Err(DecodeError::NonStringKey { offset: self.pos });
+                    }
+                    let key_start = self.pos;
+                    let key = self.decode_string()?;
+                    if self.peek("a dictionary value")? == b'e' {
+                        return Err(DecodeError::UnexpectedByte { offset: self.pos, found: b'e', expected: "a dictionary value" });
+                    }
+                    if self.strict {
+                        let key_string = String::from_utf8_lossy(key).into_owned();
+                        if map.contains_key(key) {
+                            self.violation(key_start, ViolationKind::DuplicateKey(key_string.clone()));
+                        } else if previous_key.is_some_and(|previous| key < previous) {
+                            self.violation(key_start, ViolationKind::UnsortedKey(key_string.clone()));
+                        }
+                        self.path.push(key_string);
+                    }
+                    previous_key = Some(key);
+                    let value_start = self.pos;
+                    let val = self.decode_value()?;
+                    if self.strict {
+                        self.path.pop();
+                    }
+                    map.insert(key, val, &self.input[value_start..self.pos]);
+                }
+                self.pos += 1;
+                self.budget.leave();
+                Ok(ValueRef::Map(map))
+            },
+            _ => Err(DecodeError::UnexpectedByte { offset: self.pos, found: first, expected: "a string length, 'i', 'l' or 'd'" }),
+        }
+    }
+
+    // Example: "5:hello" -> "hello"
+    fn decode_string(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        let digits = self.input[start..].iter().take_while(|u| u.is_ascii_digit()).count();
+        let colon_index = start + digits;
+        match self.input.get(colon_index) {
+            Some(b':') => {},
+            Some(found) => return Err(DecodeError::UnexpectedByte { offset: colon_index, found: *found, expected: "':' after string length" }),
+            None => return Err(DecodeError::UnexpectedEof { offset: colon_index, expected: "':' after string length" }),
+        }
+        let number = std::str::from_utf8(&self.input[start..colon_index]).ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or(DecodeError::InvalidLength { offset: start })?;
+        self.budget.string(start, number)?;
+        if self.strict && digits > 1 && self.input[start] == b'0' {
+            self.violation(start, ViolationKind::LeadingZeroLength);
+        }
+        let string_start = colon_index + 1;
+        let end = string_start.checked_add(number)
+            .filter(|end| *end <= self.input.len())
+            .ok_or(DecodeError::UnexpectedEof { offset: self.input.len(), expected: "string contents" })?;
+        self.pos = end;
+        Ok(&self.input[string_start..end])
+    }
+
+    // Example: "i-52e" -> -52
+    fn decode_int(&mut self) -> Result<ValueRef<'a>, DecodeError> {
+        let start = self.pos;
+        let e_index = find_index(&self.input[start..], b'e')
+            .map(|i| start + i)
+            .ok_or(DecodeError::UnexpectedEof { offset: self.input.len(), expected: "'e' after integer" })?;
+        let digits = &self.input[start + 1..e_index];
+        self.budget.integer_digits(start, digits.len())?;
+        let number = parse_int(digits).ok_or(DecodeError::InvalidInteger { offset: start })?;
+        if self.strict {
+            let magnitude = digits.strip_prefix(b"-").unwrap_or(digits);
+            if digits == b"-0" {
+                self.violation(start, ViolationKind::NegativeZero);
+            } else if magnitude.len() > 1 && magnitude[0] == b'0' {
+                self.violation(start, ViolationKind::LeadingZeroInteger);
             }
-            bencoded.push('e' as u8);
-            bencoded
         }
+        self.pos = e_index + 1;
+        Ok(number.map_or_else(ValueRef::BigInt, ValueRef::Int))
     }
 }
 
-pub fn decode_bencoded_value(encoded_value: &[u8]) -> (Value, Vec<u8>) {
-    if is_digit(encoded_value[0] as char) {
-        // Example: "5:hello" -> "hello"
-        let colon_index = find_index(encoded_value, ':');
-        let number_string = String::from_utf8(encoded_value[..colon_index].to_vec()).unwrap();
-        let number = number_string.parse::<usize>().unwrap();
-        let string = &encoded_value[colon_index + 1..colon_index + 1 + number];
-        return (Value::String(string.to_vec()), encoded_value[colon_index + 1 + number..].to_vec());
-    } else if encoded_value[0] as char == 'i' {
-        // Example: "i-52e" -> -52
-        let i_index = find_index(encoded_value, 'i');
-        let e_index = find_index(encoded_value, 'e');
-        let number_string = String::from_utf8(encoded_value[i_index + 1..e_index].to_vec()).unwrap();
-        let number = number_string.parse::<i64>().unwrap();
-        return (Value::Int(number), encoded_value[e_index+1..].into());
-    } else if encoded_value[0] as char == 'l' {
-        // Example: "l5:helloi52ee" -> ["hello",52]
-        let mut current_string = encoded_value[1..].to_vec();
-        let mut value_list = vec![];
-        while current_string[0] as char != 'e' {
-            let (value, rest) = decode_bencoded_value(&current_string);
-            value_list.push(value);
-            current_string = rest;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::stream::StreamDecoder;
+
+    const TIGHT: Limits = Limits { max_depth: 2, max_alloc: 1024, max_string_len: 8, max_elements: 4 };
+
+    // Decodes `input` with both decoders, which must agree on the outcome.
+    fn decode_both(input: &[u8], limits: Limits) -> Result<Value, DecodeError> {
+        let sliced = decode_with_limits(input, limits).map(|(value, _)| value);
+        let streamed = StreamDecoder::with_limits(input, limits).read_value().map(Option::unwrap).map_err(|err| match err {
+            crate::modules::stream::StreamError::Decode(err) => err,
+            err => panic!("unexpected {}", err),
+        });
+        assert_eq!(sliced, streamed, "{:?}", String::from_utf8_lossy(input));
+        sliced
+    }
+
+    fn limit(input: &[u8], limits: Limits) -> (usize, &'static str) {
+        match decode_both(input, limits) {
+            Err(DecodeError::LimitExceeded { offset, limit }) => (offset, limit),
+            other => panic!("expected a limit error, got {:?}", other),
         }
-        return (Value::List(value_list), current_string[1..].into())
-    } else if encoded_value[0] as char == 'd' {
-        // Example: d3:foo3:bar5:helloi52ee
-        let mut map = Map::new();
-        let mut current_string = encoded_value[1..].to_vec();
-        while current_string[0] as char != 'e' {
-            let (key, rest) = decode_bencoded_value(&current_string);
-            current_string = rest;
-            if let Value::String(key) = key {
-                let (val, rest) = decode_bencoded_value(&current_string);
-                map.insert(key, val);
-                current_string = rest;
-            } else {
-                panic!("Dictionary keys should be Strings.");
-            }
+    }
+
+    fn error(input: &[u8]) -> DecodeError {
+        decode_bencoded_value(input).unwrap_err()
+    }
+
+    #[test]
+    fn reports_where_truncated_input_ends() {
+        assert_eq!(error(b""), DecodeError::UnexpectedEof { offset: 0, expected: "a value" });
+        assert_eq!(error(b"5:ab"), DecodeError::UnexpectedEof { offset: 4, expected: "string contents" });
+        assert_eq!(error(b"12"), DecodeError::UnexpectedEof { offset: 2, expected: "':' after string length" });
+        assert_eq!(error(b"i42"), DecodeError::UnexpectedEof { offset: 3, expected: "'e' after integer" });
+        assert_eq!(error(b"li1e"), DecodeError::UnexpectedEof { offset: 4, expected: "a list element or 'e'" });
+        assert_eq!(error(b"d3:foo"), DecodeError::UnexpectedEof { offset: 6, expected: "a dictionary value" });
+        assert_eq!(error(b"d3:foo3:bar").to_string(), "unexpected end of input at byte 11, expected a dictionary key or 'e'");
+    }
+
+    #[test]
+    fn reports_the_offset_of_invalid_integers() {
+        for (input, offset) in [(&b"ie"[..], 0), (b"i-e", 0), (b"i1-2e", 0), (b"li1ei--3ee", 4), (b"d1:ai1.5ee", 4)] {
+            assert_eq!(error(input), DecodeError::InvalidInteger { offset }, "{:?}", String::from_utf8_lossy(input));
         }
-        return (Value::Map(map), current_string[1..].into());
-    } else {
-        panic!("Unhandled encoded value: {}", String::from_utf8(encoded_value.to_vec()).unwrap())
+        assert_eq!(error(b"li1eix\x00e").to_string(), "invalid integer at byte 4");
+    }
+
+    #[test]
+    fn reports_malformed_structure() {
+        assert_eq!(error(b"x"), DecodeError::UnexpectedByte { offset: 0, found: b'x', expected: "a string length, 'i', 'l' or 'd'" });
+        assert_eq!(error(b"3x"), DecodeError::UnexpectedByte { offset: 1, found: b'x', expected: "':' after string length" });
+        assert_eq!(error(b"di1ei2ee"), DecodeError::NonStringKey { offset: 1 });
+        assert_eq!(error(b"d1:ae"), DecodeError::UnexpectedByte { offset: 4, found: b'e', expected: "a dictionary value" });
+        assert_eq!(error(b"99999999999999999999999:"), DecodeError::InvalidLength { offset: 0 });
+        assert_eq!(error(b"l\x01").to_string(), "unexpected byte 0x01 at byte 1, expected a string length, 'i', 'l' or 'd'");
+    }
+
+    #[test]
+    fn returns_the_input_after_the_value() {
+        let (value, rest) = decode_bencoded_value(b"i7e3:abc").unwrap();
+        assert_eq!((value, rest), (Value::Int(7), &b"3:abc"[..]));
+    }
+
+    #[test]
+    fn borrows_strings_from_the_input() {
+        let input = b"l5:helloi-3ed1:k1:vee".to_vec();
+        let (ValueRef::List(list), rest) = decode_bencoded_ref(&input).unwrap() else {
+            panic!("expected a list")
+        };
+        assert!(rest.is_empty());
+        let ValueRef::String(hello) = &list[0] else {
+            panic!("expected a string")
+        };
+        assert_eq!(*hello, b"hello");
+        assert_eq!(hello.as_ptr(), input[3..].as_ptr());
+        assert_eq!(list[1].to_owned_value(), Value::Int(-3));
+        assert_eq!(decode_bencoded_ref(&input).unwrap().0.to_owned_value(), decode_bencoded_value(&input).unwrap().0);
+    }
+
+    #[test]
+    fn keeps_the_raw_bytes_of_non_canonical_info() {
+        // unsorted keys and a padded integer: re-encoding the decoded dictionary gives different bytes
+        let info = b"d4:name1:x6:lengthi010e12:piece lengthi16e6:pieces0:e";
+        let torrent = [&b"d8:announce3:url4:info"[..], info, b"7:comment2:hie"].concat();
+        let (ValueRef::Map(map), _) = decode_bencoded_ref(&torrent).unwrap() else {
+            panic!("expected a dictionary")
+        };
+        assert_eq!(map.get_raw("info"), Some(&info[..]));
+        assert_eq!(map.get_raw("comment"), Some(&b"2:hi"[..]));
+        assert_ne!(encode_value(map.get("info").unwrap().to_owned_value()), info);
+        // the span decodes back to the same value
+        let (reparsed, rest) = decode_bencoded_value(map.get_raw("info").unwrap()).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(reparsed, map.get("info").unwrap().to_owned_value());
     }
-}
\ No newline at end of file
+
+    fn violations(input: &[u8]) -> Vec<(String, usize, ViolationKind)> {
+        match decode_strict(input) {
+            Err(StrictError::NonCanonical(violations)) => violations.into_iter().map(|v| (v.path, v.offset, v.kind)).collect(),
+            other => panic!("expected violations, got {:?}", other.map(|value| value.to_owned_value())),
+        }
+    }
+
+    #[test]
+    fn accepts_canonical_input_strictly() {
+        assert!(decode_strict(b"d1:ai0e1:bli-1ei10e0:ee").is_ok());
+    }
+
+    #[test]
+    fn rejects_negative_zero_and_padded_integers() {
+        assert_eq!(violations(b"i-0e"), [(String::new(), 0, ViolationKind::NegativeZero)]);
+        assert_eq!(violations(b"i03e"), [(String::new(), 0, ViolationKind::LeadingZeroInteger)]);
+        assert_eq!(violations(b"i-03e"), [(String::new(), 0, ViolationKind::LeadingZeroInteger)]);
+        // the lenient decoder still reads them
+        assert_eq!(decode_bencoded_value(b"i-0e").unwrap().0, Value::Int(0));
+        assert_eq!(decode_bencoded_value(b"i03e").unwrap().0, Value::Int(3));
+    }
+
+    #[test]
+    fn reports_each_violation_with_its_path() {
+        assert_eq!(violations(b"d1:bi1e1:ai2ee"), [(String::new(), 7, ViolationKind::UnsortedKey("a".into()))]);
+        assert_eq!(violations(b"d1:ad1:xi1e1:xi2eee"), [("a".into(), 11, ViolationKind::DuplicateKey("x".into()))]);
+        assert_eq!(violations(b"d4:infod5:filesld6:lengthi-0eeeee"), [("info.files[0].length".into(), 25, ViolationKind::NegativeZero)]);
+        assert_eq!(violations(b"li1ei007ee"), [("[1]".into(), 4, ViolationKind::LeadingZeroInteger)]);
+        assert_eq!(violations(b"d1:a02:hie"), [("a".into(), 4, ViolationKind::LeadingZeroLength)]);
+        assert_eq!(violations(b"i1egarbage"), [(String::new(), 3, ViolationKind::TrailingData(7))]);
+    }
+
+    #[test]
+    fn collects_every_violation() {
+        let found = violations(b"d1:bi-0e1:a01:xe!");
+        let kinds: Vec<ViolationKind> = found.into_iter().map(|(_, _, kind)| kind).collect();
+        assert_eq!(kinds, [
+            ViolationKind::NegativeZero,
+            ViolationKind::UnsortedKey("a".into()),
+            ViolationKind::LeadingZeroLength,
+            ViolationKind::TrailingData(1),
+        ]);
+        let violation = Violation { path: "info.name".into(), offset: 9, kind: ViolationKind::LeadingZeroLength };
+        assert_eq!(violation.to_string(), "info.name (byte 9): string length has leading zeros");
+    }
+
+    #[test]
+    fn enforces_the_nesting_depth() {
+        assert!(decode_both(b"llee", TIGHT).is_ok());
+        assert_eq!(limit(b"llleee", TIGHT), (2, "nesting depth"));
+        assert_eq!(limit(b"ld1:ald", TIGHT), (5, "nesting depth"));
+    }
+
+    #[test]
+    fn enforces_the_string_length_before_reading_it() {
+        assert!(decode_both(b"8:12345678", TIGHT).is_ok());
+        assert_eq!(limit(b"l9:123456789e", TIGHT), (1, "string length"));
+        // the contents never arrive, the claimed length alone is too much
+        assert_eq!(limit(b"999999999:", TIGHT), (0, "string length"));
+    }
+
+    #[test]
+    fn enforces_the_element_count() {
+        assert!(decode_both(b"li1ei2ei3ee", TIGHT).is_ok());
+        assert_eq!(limit(b"li1ei2ei3ei4ee", TIGHT), (10, "element count"));
+    }
+
+    #[test]
+    fn enforces_the_total_allocation() {
+        let limits = Limits { max_alloc: 3 * std::mem::size_of::<Value>() + 10, max_elements: usize::MAX, ..TIGHT };
+        assert!(decode_both(b"l5:abcde5:abcdee", limits).is_ok());
+        assert_eq!(limit(b"l5:abcde5:abcde1:xe", limits), (15, "allocation"));
+    }
+
+    #[test]
+    fn bounds_integer_digits_like_strings() {
+        assert!(decode_both(b"i-1234567e", TIGHT).is_ok());
+        assert_eq!(limit(b"i123456789e", TIGHT), (0, "integer length"));
+    }
+}
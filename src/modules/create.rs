@@ -0,0 +1,197 @@
+use std::{fs::{self, File}, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, thread, time::{SystemTime, UNIX_EPOCH}};
+
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::modules::value::{Map, Value};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+// Automatic piece lengths aim for about this many pieces.
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Debug, Error)]
+pub enum CreateError {
+    #[error("{}: {source}", .path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{} has no content to hash", .0.display())]
+    Empty(PathBuf),
+    #[error("piece length {0} must be a power of two of at least 16 KiB")]
+    InvalidPieceLength(u64),
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> CreateError + '_ {
+    move |source| CreateError::Io { path: path.to_path_buf(), source }
+}
+
+/// What to put in a torrent besides the content itself.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    /// Chosen from the content size when `None`.
+    pub piece_length: Option<u64>,
+    /// Tracker tiers; the first tracker also becomes `announce`.
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub private: bool,
+    pub source: Option<String>,
+    /// Web seed URLs (BEP 19).
+    pub web_seeds: Vec<String>,
+    /// Leaves out files and directories whose name starts with a dot.
+    pub skip_hidden: bool,
+}
+
+struct SourceFile {
+    disk_path: PathBuf,
+    path: Vec<String>,
+    length: u64,
+    offset: u64,
+}
+
+/// Picks a power of two between 16 KiB and 16 MiB that splits `total_length` into roughly 1500 pieces.
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECES).next_power_of_two().clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Builds the metainfo for the file or directory at `path`, hashing pieces on every available core.
+/// Encode the result with `encode_value` to get the `.torrent` file.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Value, CreateError> {
+    let metadata = fs::metadata(path).map_err(io_error(path))?;
+    let name = path.canonicalize().map_err(io_error(path))?
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| CreateError::Empty(path.to_path_buf()))?;
+    let mut files = vec![];
+    if metadata.is_dir() {
+        collect_files(path, &mut vec![], options.skip_hidden, &mut files)?;
+    } else {
+        files.push(SourceFile { disk_path: path.to_path_buf(), path: vec![name.clone()], length: metadata.len(), offset: 0 });
+    }
+    let mut total_length = 0;
+    for file in &mut files {
+        file.offset = total_length;
+        total_length += file.length;
+    }
+    if total_length == 0 {
+        return Err(CreateError::Empty(path.to_path_buf()));
+    }
+    let piece_length = match options.piece_length {
+        Some(length) if length < MIN_PIECE_LENGTH || !length.is_power_of_two() => return Err(CreateError::InvalidPieceLength(length)),
+        Some(length) => length,
+        None => auto_piece_length(total_length),
+    };
+
+    let mut info = Map::new();
+    info.insert("name", name);
+    info.insert("piece length", piece_length);
+    info.insert("pieces", hash_pieces(&files, total_length, piece_length)?);
+    if metadata.is_dir() {
+        let file_list = files.iter().map(|file| {
+            let mut entry = Map::new();
+            entry.insert("length", file.length);
+            entry.insert("path", file.path.iter().map(|component| Value::from(component.as_str())).collect::<Vec<_>>());
+            Value::Map(entry)
+        });
+        info.insert("files", file_list.collect::<Vec<_>>());
+    } else {
+        info.insert("length", total_length);
+    }
+    if options.private {
+        info.insert("private", 1);
+    }
+    if let Some(source) = &options.source {
+        info.insert("source", source.as_str());
+    }
+
+    let mut torrent = Map::new();
+    let mut trackers = options.trackers.iter().flatten();
+    if let Some(announce) = trackers.next() {
+        torrent.insert("announce", announce.as_str());
+    }
+    if trackers.next().is_some() {
+        let tiers = options.trackers.iter()
+            .map(|tier| Value::List(tier.iter().map(|url| Value::from(url.as_str())).collect()))
+            .collect::<Vec<_>>();
+        torrent.insert("announce-list", tiers);
+    }
+    if let Some(comment) = &options.comment {
+        torrent.insert("comment", comment.as_str());
+    }
+    torrent.insert("created by", format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")));
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        torrent.insert("creation date", now.as_secs());
+    }
+    if !options.web_seeds.is_empty() {
+        torrent.insert("url-list", options.web_seeds.iter().map(|url| Value::from(url.as_str())).collect::<Vec<_>>());
+    }
+    torrent.insert("info", info);
+    Ok(Value::Map(torrent))
+}
+
+// Walks `dir` in name order so the same directory always produces the same torrent. Symlinks are
+// skipped: following them could loop forever or pull in files from outside the tree.
+fn collect_files(dir: &Path, prefix: &mut Vec<String>, skip_hidden: bool, files: &mut Vec<SourceFile>) -> Result<(), CreateError> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(io_error(dir))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if skip_hidden && name.starts_with('.') {
+            continue;
+        }
+        let disk_path = entry.path();
+        let metadata = fs::symlink_metadata(&disk_path).map_err(io_error(&disk_path))?;
+        if metadata.is_symlink() {
+            continue;
+        }
+        prefix.push(name);
+        if metadata.is_dir() {
+            collect_files(&disk_path, prefix, skip_hidden, files)?;
+        } else {
+            files.push(SourceFile { disk_path, path: prefix.clone(), length: metadata.len(), offset: 0 });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+// Splits the pieces between one thread per core; each thread reads its pieces straight from the files.
+fn hash_pieces(files: &[SourceFile], total_length: u64, piece_length: u64) -> Result<Vec<u8>, CreateError> {
+    let piece_count = total_length.div_ceil(piece_length) as usize;
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(piece_count);
+    let pieces_per_thread = piece_count.div_ceil(threads);
+    let mut hashes = vec![0u8; piece_count * 20];
+    thread::scope(|scope| {
+        let workers: Vec<_> = hashes.chunks_mut(pieces_per_thread * 20)
+            .enumerate()
+            .map(|(worker, output)| scope.spawn(move || {
+                let mut piece = vec![];
+                for (i, hash) in output.chunks_mut(20).enumerate() {
+                    let start = (worker * pieces_per_thread + i) as u64 * piece_length;
+                    read_range(files, start, piece_length.min(total_length - start), &mut piece)?;
+                    hash.copy_from_slice(&Sha1::digest(&piece));
+                }
+                Ok(())
+            }))
+            .collect();
+        workers.into_iter().try_for_each(|worker| worker.join().expect("hashing thread panicked"))
+    })?;
+    Ok(hashes)
+}
+
+fn read_range(files: &[SourceFile], start: u64, length: u64, buffer: &mut Vec<u8>) -> Result<(), CreateError> {
+    buffer.clear();
+    let end = start + length;
+    for file in files.iter().filter(|file| file.offset < end && file.offset + file.length > start) {
+        let from = start.max(file.offset) - file.offset;
+        let to = end.min(file.offset + file.length) - file.offset;
+        let mut handle = File::open(&file.disk_path).map_err(io_error(&file.disk_path))?;
+        handle.seek(SeekFrom::Start(from)).map_err(io_error(&file.disk_path))?;
+        let read = handle.take(to - from).read_to_end(buffer).map_err(io_error(&file.disk_path))?;
+        if (read as u64) < to - from {
+            let source = io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while hashing");
+            return Err(CreateError::Io { path: file.disk_path.clone(), source });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+use serde_json::{json, Value as Json};
+
+use crate::modules::{json::value_to_json, value::{format_path, PathSegment, Value}};
+
+const PIECE_HASH_LEN: usize = 20;
+// Values longer than this when rendered as JSON are summarized instead of printed.
+const MAX_RENDERED_LEN: usize = 80;
+
+#[derive(Debug)]
+pub enum Change<'a> {
+    Added(&'a Value),
+    Removed(&'a Value),
+    Changed { old: &'a Value, new: &'a Value },
+    /// A `pieces` string that differs, compared hash by hash.
+    Pieces { changed: Vec<usize>, old_count: usize, new_count: usize },
+}
+
+#[derive(Debug)]
+pub struct Difference<'a> {
+    pub path: Vec<PathSegment>,
+    pub change: Change<'a>,
+}
+
+impl fmt::Display for Difference<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = format_path(&self.path);
+        let path = if path.is_empty() { "<root>".into() } else { path };
+        match &self.change {
+            Change::Added(value) => write!(f, "+ {}: {}", path, render(value)),
+            Change::Removed(value) => write!(f, "- {}: {}", path, render(value)),
+            Change::Changed { old, new } => write!(f, "~ {}: {} -> {}", path, render(old), render(new)),
+            Change::Pieces { changed, old_count, new_count } => {
+                write!(f, "~ {}: ", path)?;
+                if !changed.is_empty() {
+                    write!(f, "{} piece hash(es) differ ({})", changed.len(), format_ranges(changed))?;
+                }
+                if old_count != new_count {
+                    if !changed.is_empty() {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "piece count {} -> {}", old_count, new_count)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Difference<'_> {
+    /// The difference for `--json`, with values in the lossless JSON form of [`value_to_json`].
+    pub fn to_json(&self) -> Json {
+        let path = format_path(&self.path);
+        match &self.change {
+            Change::Added(value) => json!({ "path": path, "change": "added", "new": value_to_json(value) }),
+            Change::Removed(value) => json!({ "path": path, "change": "removed", "old": value_to_json(value) }),
+            Change::Changed { old, new } => json!({ "path": path, "change": "changed", "old": value_to_json(old), "new": value_to_json(new) }),
+            Change::Pieces { changed, old_count, new_count } => json!({
+                "path": path, "change": "pieces", "changed_pieces": changed, "old_count": old_count, "new_count": new_count,
+            }),
+        }
+    }
+}
+
+fn render(value: &Value) -> String {
+    let json = value_to_json(value).to_string();
+    if json.len() <= MAX_RENDERED_LEN {
+        return json;
+    }
+    match value {
+        Value::String(s) => format!("<{} byte string>", s.len()),
+        Value::List(list) => format!("<list of {} elements>", list.len()),
+        Value::Map(map) => format!("<dictionary with {} keys>", map.len()),
+        Value::Int(_) | Value::BigInt(_) => json,
+    }
+}
+
+// Example: [1, 2, 3, 7] -> "1-3, 7"
+fn format_ranges(indexes: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &index in indexes {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    let ranges: Vec<String> = ranges.iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect();
+    ranges.join(", ")
+}
+
+/// Walks both documents and lists every path that was added, removed or changed going from `old` to `new`.
+/// `pieces` strings are compared per piece hash rather than as one opaque string.
+pub fn diff_values<'a>(old: &'a Value, new: &'a Value) -> Vec<Difference<'a>> {
+    let mut differences = vec![];
+    diff_at(&mut vec![], old, new, &mut differences);
+    differences
+}
+
+fn diff_at<'a>(path: &mut Vec<PathSegment>, old: &'a Value, new: &'a Value, differences: &mut Vec<Difference<'a>>) {
+    match (old, new) {
+        (Value::Map(old_map), Value::Map(new_map)) => {
+            for (key, old_value) in old_map {
+                path.push(PathSegment::Key(key.clone()));
+                match new_map.get_bytes(key) {
+                    Some(new_value) => diff_at(path, old_value, new_value, differences),
+                    None => differences.push(Difference { path: path.clone(), change: Change::Removed(old_value) }),
+                }
+                path.pop();
+            }
+            for (key, new_value) in new_map {
+                if old_map.get_bytes(key).is_none() {
+                    let mut path = path.clone();
+                    path.push(PathSegment::Key(key.clone()));
+                    differences.push(Difference { path, change: Change::Added(new_value) });
+                }
+            }
+        },
+        (Value::List(old_list), Value::List(new_list)) => {
+            for (index, old_value) in old_list.iter().enumerate() {
+                path.push(PathSegment::Index(index));
+                match new_list.get(index) {
+                    Some(new_value) => diff_at(path, old_value, new_value, differences),
+                    None => differences.push(Difference { path: path.clone(), change: Change::Removed(old_value) }),
+                }
+                path.pop();
+            }
+            for (index, new_value) in new_list.iter().enumerate().skip(old_list.len()) {
+                let mut path = path.clone();
+                path.push(PathSegment::Index(index));
+                differences.push(Difference { path, change: Change::Added(new_value) });
+            }
+        },
+        (Value::String(old_pieces), Value::String(new_pieces)) if old_pieces != new_pieces && is_pieces(path, old_pieces, new_pieces) => {
+            let old_hashes: Vec<&[u8]> = old_pieces.chunks(PIECE_HASH_LEN).collect();
+            let new_hashes: Vec<&[u8]> = new_pieces.chunks(PIECE_HASH_LEN).collect();
+            let changed = old_hashes.iter().zip(&new_hashes)
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(index, _)| index)
+                .collect();
+            let change = Change::Pieces { changed, old_count: old_hashes.len(), new_count: new_hashes.len() };
+            differences.push(Difference { path: path.clone(), change });
+        },
+        (old, new) if old != new => differences.push(Difference { path: path.clone(), change: Change::Changed { old, new } }),
+        _ => {},
+    }
+}
+
+fn is_pieces(path: &[PathSegment], old: &[u8], new: &[u8]) -> bool {
+    matches!(path.last(), Some(PathSegment::Key(key)) if key == b"pieces")
+        && old.len().is_multiple_of(PIECE_HASH_LEN)
+        && new.len().is_multiple_of(PIECE_HASH_LEN)
+}
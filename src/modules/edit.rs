@@ -0,0 +1,184 @@
+use thiserror::Error;
+
+use crate::modules::{bencode::{decode_bencoded_ref, encode_value, DecodeError}, torrent::Torrent, value::{Map, PathError, Value}, value_serde};
+
+#[derive(Debug, Error)]
+pub enum EditError {
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Path(#[from] PathError),
+    #[error(transparent)]
+    Torrent(#[from] value_serde::Error),
+    #[error("a torrent must be a dictionary")]
+    NotADictionary,
+    #[error("{0} must be {1}")]
+    InvalidField(&'static str, &'static str),
+    #[error("the edits change the info dictionary, and with it the info hash; force the edit to do it anyway")]
+    TouchesInfo,
+}
+
+/// One change to a torrent's metadata.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    SetAnnounce(String),
+    /// Appends a new tier holding these trackers.
+    AddTier(Vec<String>),
+    /// Drops the tracker from `announce` and every tier.
+    RemoveTracker(String),
+    ReplaceTracker { old: String, new: String },
+    SetComment(Option<String>),
+    SetCreatedBy(Option<String>),
+    AddWebSeed(String),
+    RemoveWebSeed(String),
+    ClearWebSeeds,
+    /// Stores a value at a path, see [`Value::set_pointer`].
+    Set { path: String, value: Value },
+    Remove(String),
+}
+
+/// Applies `edits` to the encoded torrent. As long as the edits leave `info` alone, the result is written
+/// by [`Torrent::to_bytes`], which copies the original info bytes untouched, so the info hash stays the
+/// same even for a non-canonical dictionary. Edits that change `info` fail with [`EditError::TouchesInfo`]
+/// unless `force` is set.
+pub fn edit_torrent(encoded: &[u8], edits: &[Edit], force: bool) -> Result<Vec<u8>, EditError> {
+    let (value, _) = decode_bencoded_ref(encoded)?;
+    let torrent = Torrent::new(&value)?;
+    let original_info = torrent.info.to_value();
+    let mut edited = torrent.to_value();
+    for edit in edits {
+        apply_edit(&mut edited, edit)?;
+    }
+    let Value::Map(edited) = edited else {
+        return Err(EditError::NotADictionary);
+    };
+    if edited.get("info") == Some(&original_info) {
+        return Ok(torrent.with_metadata(edited)?.to_bytes());
+    }
+    if !force {
+        return Err(EditError::TouchesInfo);
+    }
+    Ok(encode_value(Value::Map(edited)))
+}
+
+fn apply_edit(torrent: &mut Value, edit: &Edit) -> Result<(), EditError> {
+    let Value::Map(map) = torrent else {
+        return Err(EditError::NotADictionary);
+    };
+    match edit {
+        Edit::SetAnnounce(url) => map.insert("announce", url.as_str()),
+        Edit::AddTier(urls) => {
+            let mut tiers = tiers(map)?;
+            // keep the existing announce URL reachable once clients switch over to announce-list
+            if tiers.is_empty() {
+                if let Some(announce) = map.get("announce").and_then(Value::as_str) {
+                    tiers.push(vec![announce.to_string()]);
+                }
+            }
+            tiers.push(urls.clone());
+            set_trackers(map, tiers);
+        },
+        Edit::RemoveTracker(url) => {
+            let mut tiers = tiers(map)?;
+            for tier in &mut tiers {
+                tier.retain(|tracker| tracker != url);
+            }
+            if map.get("announce").and_then(Value::as_str) == Some(url) {
+                map.remove("announce");
+            }
+            set_trackers(map, tiers);
+        },
+        Edit::ReplaceTracker { old, new } => {
+            let mut tiers = tiers(map)?;
+            for tracker in tiers.iter_mut().flatten().filter(|tracker| *tracker == old) {
+                tracker.clone_from(new);
+            }
+            if map.get("announce").and_then(Value::as_str) == Some(old) {
+                map.insert("announce", new.as_str());
+            }
+            set_trackers(map, tiers);
+        },
+        Edit::SetComment(comment) => set_or_clear(map, "comment", comment),
+        Edit::SetCreatedBy(created_by) => set_or_clear(map, "created by", created_by),
+        Edit::AddWebSeed(url) => {
+            let mut urls = web_seeds(map)?;
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+            set_web_seeds(map, urls);
+        },
+        Edit::RemoveWebSeed(url) => {
+            let mut urls = web_seeds(map)?;
+            urls.retain(|seed| seed != url);
+            set_web_seeds(map, urls);
+        },
+        Edit::ClearWebSeeds => {
+            map.remove("url-list");
+        },
+        Edit::Set { path, value } => {
+            torrent.set_pointer(path, value.clone())?;
+        },
+        Edit::Remove(path) => {
+            torrent.remove_pointer(path)?;
+        },
+    }
+    Ok(())
+}
+
+fn set_or_clear(map: &mut Map, key: &str, value: &Option<String>) {
+    match value {
+        Some(value) => map.insert(key, value.as_str()),
+        None => {
+            map.remove(key);
+        },
+    }
+}
+
+fn strings(value: &Value) -> Option<Vec<String>> {
+    value.as_list()?.iter().map(|item| item.as_str().map(String::from)).collect()
+}
+
+fn tiers(map: &Map) -> Result<Vec<Vec<String>>, EditError> {
+    let Some(list) = map.get("announce-list") else {
+        return Ok(vec![]);
+    };
+    list.as_list()
+        .and_then(|tiers| tiers.iter().map(strings).collect())
+        .ok_or(EditError::InvalidField("announce-list", "a list of lists of URLs"))
+}
+
+// Writes the tiers back without empty ones, dropping announce-list altogether when nothing is left, and
+// points announce at the first remaining tracker if it lost its own.
+fn set_trackers(map: &mut Map, tiers: Vec<Vec<String>>) {
+    let tiers: Vec<Vec<String>> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+    if !map.contains_key("announce") {
+        if let Some(first) = tiers.first().and_then(|tier| tier.first()) {
+            map.insert("announce", first.as_str());
+        }
+    }
+    if tiers.is_empty() {
+        map.remove("announce-list");
+    } else {
+        let tiers: Vec<Value> = tiers.iter()
+            .map(|tier| Value::List(tier.iter().map(|url| Value::from(url.as_str())).collect()))
+            .collect();
+        map.insert("announce-list", tiers);
+    }
+}
+
+fn web_seeds(map: &Map) -> Result<Vec<String>, EditError> {
+    match map.get("url-list") {
+        None => Ok(vec![]),
+        Some(Value::String(_)) => map.get("url-list").and_then(Value::as_str).map(|url| vec![url.to_string()])
+            .ok_or(EditError::InvalidField("url-list", "a URL or a list of URLs")),
+        Some(list) => strings(list).ok_or(EditError::InvalidField("url-list", "a URL or a list of URLs")),
+    }
+}
+
+fn set_web_seeds(map: &mut Map, urls: Vec<String>) {
+    if urls.is_empty() {
+        map.remove("url-list");
+    } else {
+        map.insert("url-list", urls.iter().map(|url| Value::from(url.as_str())).collect::<Vec<_>>());
+    }
+}
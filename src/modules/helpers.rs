@@ -1,24 +1,38 @@
-use std::{io::{Read, Write}, net::TcpStream};
-use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
-use sha1::{Digest, Sha1};
-
-use crate::{bytes_to_peer_list, modules::{bencode::decode_bencoded_value, torrent::Torrent}};
-
-pub fn get_peers(announce: &str, info_hash: &[u8], peer_id: &str, file_size: usize) -> Vec<(String, u16)> {
-    let req_url = String::from(announce);
-    let info_hash_encoded = percent_encode(&info_hash, NON_ALPHANUMERIC).to_string();
-    let port = 6881;
-    let query_params = format!("?info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&compact=1", info_hash_encoded, peer_id, port, file_size);
-    let url = req_url + &query_params;
-    let result = reqwest::blocking::get(url);
-    if let Err(err) = &result {
-        println!("Error: {}", err.status().unwrap());
+use std::{collections::BTreeMap, io::{self, Read, Write}, net::{TcpStream, ToSocketAddrs}, time::Duration};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::modules::torrent::Torrent;
+
+// How long a peer gets to accept the connection, and to send anything once connected.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("expected a {expected} message, got message {got}")]
+    UnexpectedMessage { expected: &'static str, got: u8 },
+    #[error("sent a message of {0} bytes")]
+    TooLong(usize),
+    #[error("piece {0} doesn't match its hash")]
+    HashMismatch(usize),
+}
+
+/// Dictionary sent by a peer in its extension protocol handshake (BEP 10).
+#[derive(Deserialize)]
+pub struct ExtensionHandshake {
+    /// Extension name to the message id the peer wants it sent with. Ids are kept as sent, so an
+    /// out-of-range id for some other extension doesn't spoil the whole handshake.
+    pub m: BTreeMap<String, i64>,
+}
+
+impl ExtensionHandshake {
+    /// The message id for ut_metadata, if the peer supports it. Id 0 means the extension is disabled.
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.m.get("ut_metadata").and_then(|&id| u8::try_from(id).ok()).filter(|&id| id != 0)
     }
-    let response = result.unwrap().bytes().unwrap();
-    let resp_dict = decode_bencoded_value(&response).0.get_map().unwrap();
-    let peers = resp_dict.get("peers").unwrap().get_string().unwrap();
-    let peers = bytes_to_peer_list(&peers);
-    peers
 }
 
 pub fn get_handshake(info_hash: &[u8], peer_id: &str, metadata_support: bool) -> Vec<u8> {
@@ -35,9 +49,53 @@ pub fn get_handshake(info_hash: &[u8], peer_id: &str, metadata_support: bool) ->
     handshake
 }
 
-pub fn download_piece(torrent: &Torrent, self_id: &str, peer: &str, piece_index: usize) -> Vec<u8> {
+// Longest peer message we accept; a bitfield for 8 million pieces still fits.
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+// Reads one length-prefixed peer message, skipping keep-alives, and returns its id and payload.
+fn read_message(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), PeerError> {
+    loop {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length == 0 {
+            continue
+        }
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(PeerError::TooLong(length));
+        }
+        let mut message = vec![0; length];
+        stream.read_exact(&mut message)?;
+        let payload = message.split_off(1);
+        return Ok((message[0], payload))
+    }
+}
+
+fn expect_message(stream: &mut TcpStream, id: u8, name: &'static str) -> Result<Vec<u8>, PeerError> {
+    match read_message(stream)? {
+        (got, payload) if got == id => Ok(payload),
+        (got, _) => Err(PeerError::UnexpectedMessage { expected: name, got }),
+    }
+}
+
+/// Connects to `peer`, trying each address it resolves to, with a timeout on reads so a silent peer
+/// can't stall the download.
+pub fn connect(peer: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, format!("{} doesn't resolve to an address", peer));
+    for address in peer.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                return Ok(stream);
+            },
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+pub fn download_piece(torrent: &Torrent, self_id: &str, peer: &str, piece_index: usize) -> Result<Vec<u8>, PeerError> {
     let handshake = get_handshake(&torrent.info.get_info_hash_bytes(), self_id, false);
-    let piece_hash = torrent.info.get_piece(piece_index);
     let mut piece_size = torrent.info.get_piece_size();
     let file_size = torrent.info.get_file_size();
 
@@ -47,32 +105,24 @@ pub fn download_piece(torrent: &Torrent, self_id: &str, peer: &str, piece_index:
 
 
     // handshake
-    let mut stream = TcpStream::connect(peer).expect("Failed to connect");
-    stream.write_all(&handshake).expect("Failed to write to stream");
+    let mut stream = connect(peer)?;
+    stream.write_all(&handshake)?;
 
-    let mut buffer = [0; 1024 * 16 + 4 + 1 + 8];
-    stream.read_exact(&mut buffer[0..1]).expect("Failed to read from stream");
+    let mut buffer = [0; 1 + 255 + 8 + 20 + 20];
+    stream.read_exact(&mut buffer[0..1])?;
     let protocol_length = buffer[0] as usize;
-    stream.read_exact(&mut buffer[1..1+protocol_length+8+20+20]).unwrap(); // dont care about this, but we read it to free the buffer
+    stream.read_exact(&mut buffer[1..1+protocol_length+8+20+20])?; // dont care about this, but we read it to free the buffer
 
     // wait for bitfield
-    stream.read(&mut buffer).expect("Failed to read from stream");
-    let message_type = buffer[4];
-    if message_type != 5 {
-        panic!("Didn't get bitfield message {}", message_type);
-    }
+    expect_message(&mut stream, 5, "bitfield")?;
 
     // send interested
     let mut message_content = 1u32.to_be_bytes().to_vec();
     message_content.push(2u8);
-    stream.write_all(&message_content).expect("Failed to write to stream");
+    stream.write_all(&message_content)?;
 
     // wait for unchoke
-    stream.read(&mut buffer).expect("Failed to read from stream");
-    let message_type = buffer[4];
-    if message_type != 1 {
-        panic!("Didn't get unchoke message");
-    }
+    expect_message(&mut stream, 1, "unchoke")?;
 
     let max_block_size = 1024 * 16;
 
@@ -89,7 +139,7 @@ pub fn download_piece(torrent: &Torrent, self_id: &str, peer: &str, piece_index:
         message.extend((piece_index as u32).to_be_bytes());
         message.extend(offset.to_be_bytes());
         message.extend(size.to_be_bytes());
-        stream.write_all(&message).expect("Failed to write to stream");
+        stream.write_all(&message)?;
 
         i += 1;
     }
@@ -99,31 +149,40 @@ pub fn download_piece(torrent: &Torrent, self_id: &str, peer: &str, piece_index:
     // get blocks
     let mut blocks = vec![];
     for _ in 0..total_blocks {
-        stream.read_exact(&mut buffer[0..5]).expect("Failed to read");
-        let message_type = buffer[4];
-        if message_type != 7 {
-            panic!("Didn't get piece_block, got {}", message_type, )
+        let mut payload = expect_message(&mut stream, 7, "piece")?;
+        if payload.len() < 8 {
+            return Err(PeerError::UnexpectedMessage { expected: "piece", got: 7 });
         }
-        let data_size = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) - 1 - 8;
-        stream.read_exact(&mut buffer[5..13+(data_size as usize)]).expect("Failed to read");
-        let begin = u32::from_be_bytes(buffer[9..13].try_into().unwrap());
-        let block_data =  buffer[13..13+(data_size as usize)].to_vec();
+        let begin = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+        let block_data = payload.split_off(8);
         blocks.push((begin, block_data));
     }
 
-    blocks.sort_by(|a, b| a.0.cmp(&b.0));
+    blocks.sort_by_key(|block| block.0);
     let mut piece = vec![];
     for block in blocks {
         piece.extend(block.1);
     }
 
     // verify piece hash
-    let mut hasher = Sha1::new();
-    hasher.update(&piece);
-    let sha_hash = hasher.finalize();
-    let sha_hash: [u8; 20] = sha_hash.try_into().unwrap();
-    if piece_hash != sha_hash {
-        panic!("Piece hash doesn't match.")
+    if !torrent.info.verify_piece(piece_index, &piece) {
+        return Err(PeerError::HashMismatch(piece_index));
+    }
+    Ok(piece)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bencode, modules::value_serde::from_value};
+
+    #[test]
+    fn only_range_checks_the_metadata_extension() {
+        let handshake: ExtensionHandshake = from_value(bencode!({ "m": { "ut_metadata": 3, "ut_pex": 1000 } })).unwrap();
+        assert_eq!(handshake.ut_metadata(), Some(3));
+        for id in [0, 256, -1] {
+            let handshake: ExtensionHandshake = from_value(bencode!({ "m": { "ut_metadata": id } })).unwrap();
+            assert_eq!(handshake.ut_metadata(), None, "{}", id);
+        }
     }
-    piece
 }
@@ -0,0 +1,128 @@
+use serde_json::{json, Map as JsonMap, Value as Json};
+use thiserror::Error;
+
+use crate::modules::value::{BigInt, Map, Value};
+
+// Tags for the bencode values JSON can't represent directly. A byte string that isn't valid UTF-8 becomes
+// `{"$hex": "<hex>"}`. A dictionary whose keys aren't all UTF-8, or that would itself look like a tag,
+// becomes `{"$dict": [[key, value], ...]}`, and an integer outside the `i64` range becomes `{"$int": "<digits>"}`.
+const HEX_TAG: &str = "$hex";
+const DICT_TAG: &str = "$dict";
+const INT_TAG: &str = "$int";
+
+#[derive(Debug, Error)]
+pub enum JsonError {
+    #[error("{path}: {message}")]
+    Unsupported { path: String, message: String },
+    #[error(transparent)]
+    Syntax(#[from] serde_json::Error),
+}
+
+fn unsupported(path: &str, message: impl Into<String>) -> JsonError {
+    let path = if path.is_empty() { "<root>".into() } else { path.into() };
+    JsonError::Unsupported { path, message: message.into() }
+}
+
+fn child_path(path: &str, key: &str) -> String {
+    if path.is_empty() { key.into() } else { format!("{}.{}", path, key) }
+}
+
+fn string_to_json(bytes: &[u8]) -> Json {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Json::String(s.into()),
+        Err(_) => json!({ HEX_TAG: hex::encode(bytes) }),
+    }
+}
+
+/// Converts a bencode value to JSON without losing any information, see [`json_to_value`] for the reverse.
+pub fn value_to_json(value: &Value) -> Json {
+    match value {
+        Value::String(s) => string_to_json(s),
+        Value::Int(i) => Json::from(*i),
+        Value::BigInt(i) => json!({ INT_TAG: i.as_str() }),
+        Value::List(list) => Json::Array(list.iter().map(value_to_json).collect()),
+        Value::Map(map) => map_to_json(map),
+    }
+}
+
+pub fn map_to_json(map: &Map) -> Json {
+    let looks_like_tag = map.len() == 1 && map.iter().any(|(k, _)| [HEX_TAG, DICT_TAG, INT_TAG].iter().any(|tag| k == tag.as_bytes()));
+    let all_utf8 = map.iter().all(|(k, _)| std::str::from_utf8(k).is_ok());
+    if looks_like_tag || !all_utf8 {
+        let pairs = map.iter().map(|(k, v)| json!([string_to_json(k), value_to_json(v)])).collect();
+        return json!({ DICT_TAG: Json::Array(pairs) });
+    }
+    let mut object = JsonMap::new();
+    for (k, v) in map.iter() {
+        object.insert(String::from_utf8_lossy(k).into_owned(), value_to_json(v));
+    }
+    Json::Object(object)
+}
+
+fn json_to_string(json: &Json, path: &str) -> Result<Vec<u8>, JsonError> {
+    match json {
+        Json::String(s) => Ok(s.as_bytes().to_vec()),
+        Json::Object(object) if object.len() == 1 && object.contains_key(HEX_TAG) => {
+            let hex_str = object[HEX_TAG].as_str().ok_or_else(|| unsupported(path, "$hex must be a string"))?;
+            hex::decode(hex_str).map_err(|err| unsupported(path, format!("invalid $hex string: {}", err)))
+        },
+        _ => Err(unsupported(path, "expected a string or a $hex string")),
+    }
+}
+
+/// Converts JSON produced by [`value_to_json`] (or written by hand in the same form) back to bencode.
+pub fn json_to_value(json: &Json) -> Result<Value, JsonError> {
+    json_to_value_at(json, "")
+}
+
+fn json_to_value_at(json: &Json, path: &str) -> Result<Value, JsonError> {
+    match json {
+        Json::String(s) => Ok(Value::String(s.as_bytes().to_vec())),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Ok(Value::Int(i)),
+            None => n.to_string().parse::<BigInt>().map(Value::from).map_err(|_| unsupported(path, format!("{} is not an integer", n))),
+        },
+        Json::Array(list) => {
+            let mut values = vec![];
+            for (i, item) in list.iter().enumerate() {
+                values.push(json_to_value_at(item, &format!("{}[{}]", path, i))?);
+            }
+            Ok(Value::List(values))
+        },
+        Json::Object(object) if object.len() == 1 && object.contains_key(HEX_TAG) => Ok(Value::String(json_to_string(json, path)?)),
+        Json::Object(object) if object.len() == 1 && object.contains_key(INT_TAG) => {
+            let digits = object[INT_TAG].as_str().ok_or_else(|| unsupported(path, "$int must be a string of digits"))?;
+            digits.parse::<BigInt>().map(Value::from).map_err(|err| unsupported(path, err.to_string()))
+        },
+        Json::Object(object) if object.len() == 1 && object.contains_key(DICT_TAG) => {
+            let pairs = object[DICT_TAG].as_array().ok_or_else(|| unsupported(path, "$dict must be a list of [key, value] pairs"))?;
+            let mut map = Map::new();
+            for (i, pair) in pairs.iter().enumerate() {
+                let pair_path = format!("{}[{}]", path, i);
+                let Some([key, value]) = pair.as_array().map(|pair| pair.as_slice()) else {
+                    return Err(unsupported(&pair_path, "expected a [key, value] pair"));
+                };
+                let key = json_to_string(key, &pair_path)?;
+                let value_path = child_path(path, &String::from_utf8_lossy(&key));
+                map.insert(key, json_to_value_at(value, &value_path)?);
+            }
+            Ok(Value::Map(map))
+        },
+        Json::Object(object) => {
+            let mut map = Map::new();
+            for (k, v) in object {
+                let value_path = child_path(path, k);
+                map.insert(k.as_bytes().to_vec(), json_to_value_at(v, &value_path)?);
+            }
+            Ok(Value::Map(map))
+        },
+        Json::Bool(_) => Err(unsupported(path, "bencode has no booleans")),
+        Json::Null => Err(unsupported(path, "bencode has no null")),
+    }
+}
+
+/// Parses JSON text and converts it with [`json_to_value`].
+pub fn json_str_to_value(text: &[u8]) -> Result<Value, JsonError> {
+    let json: Json = serde_json::from_slice(text)?;
+    json_to_value(&json)
+}
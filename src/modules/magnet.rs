@@ -0,0 +1,347 @@
+use std::ops::RangeInclusive;
+
+use serde_json::{json, Value as Json};
+use thiserror::Error;
+
+use crate::modules::tracker::TrackerList;
+
+#[derive(Debug, Error)]
+pub enum MagnetError {
+    #[error("not a magnet link, expected it to start with `magnet:?`")]
+    NotMagnet,
+    #[error("parameter {0:?} has no value")]
+    MissingValue(String),
+    #[error("{0}: invalid percent-encoding")]
+    Encoding(String),
+    #[error("invalid btih info hash {0:?}, expected 40 hex or 32 base32 characters")]
+    InvalidInfoHash(String),
+    #[error("invalid btmh multihash {0:?}, expected a hex encoded SHA-256 multihash")]
+    InvalidMultihash(String),
+    #[error("conflicting {0} info hashes")]
+    ConflictingInfoHash(&'static str),
+    #[error("no info hash, expected an `xt` with urn:btih or urn:btmh")]
+    MissingInfoHash,
+    #[error("invalid peer address {0:?}, expected host:port")]
+    InvalidPeer(String),
+    #[error("invalid select-only list {0:?}")]
+    InvalidSelectOnly(String),
+    #[error("invalid exact length {0:?}")]
+    InvalidLength(String),
+}
+
+/// A magnet link (BEP 9), with the BEP 53 `so` and BEP 52 `btmh` extensions.
+#[derive(Debug, Clone, Default)]
+pub struct Magnet {
+    info_hash: Option<[u8; 20]>,
+    info_hash_v2: Option<[u8; 32]>,
+    display_name: Option<String>,
+    trackers: Vec<String>,
+    peers: Vec<String>,
+    web_seeds: Vec<String>,
+    select_only: Vec<RangeInclusive<usize>>,
+    exact_length: Option<u64>,
+}
+
+impl Magnet {
+    /// Parses a magnet link. Unknown parameters are ignored; numbered ones like `tr.1` count as `tr`.
+    pub fn new(magnet_link: &str) -> Result<Self, MagnetError> {
+        let params = magnet_link.strip_prefix("magnet:?").ok_or(MagnetError::NotMagnet)?;
+        let mut magnet = Self::default();
+        for param in params.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').ok_or_else(|| MagnetError::MissingValue(param.to_string()))?;
+            let name = match key.split_once('.') {
+                Some((name, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => name,
+                _ => key,
+            };
+            match name {
+                "xt" => magnet.parse_exact_topic(&percent_decode(name, value)?)?,
+                // form encoding turns spaces in names into pluses
+                "dn" => magnet.display_name = Some(percent_decode(name, &value.replace('+', " "))?),
+                "tr" => magnet.trackers.push(percent_decode(name, value)?.trim().to_string()),
+                "ws" => magnet.web_seeds.push(percent_decode(name, value)?),
+                "x.pe" => magnet.peers.push(parse_peer(&percent_decode(name, value)?)?),
+                "so" => magnet.select_only.extend(parse_select_only(&percent_decode(name, value)?)?),
+                "xl" => magnet.exact_length = Some(value.parse().map_err(|_| MagnetError::InvalidLength(value.to_string()))?),
+                _ => {},
+            }
+        }
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(MagnetError::MissingInfoHash);
+        }
+        Ok(magnet)
+    }
+
+    fn parse_exact_topic(&mut self, topic: &str) -> Result<(), MagnetError> {
+        if let Some(hash) = strip_prefix_ignore_case(topic, "urn:btih:") {
+            let parsed = parse_btih(hash).ok_or_else(|| MagnetError::InvalidInfoHash(hash.to_string()))?;
+            if self.info_hash.is_some_and(|existing| existing != parsed) {
+                return Err(MagnetError::ConflictingInfoHash("v1"));
+            }
+            self.info_hash = Some(parsed);
+        } else if let Some(multihash) = strip_prefix_ignore_case(topic, "urn:btmh:") {
+            let parsed = parse_btmh(multihash).ok_or_else(|| MagnetError::InvalidMultihash(multihash.to_string()))?;
+            if self.info_hash_v2.is_some_and(|existing| existing != parsed) {
+                return Err(MagnetError::ConflictingInfoHash("v2"));
+            }
+            self.info_hash_v2 = Some(parsed);
+        }
+        // other URNs (ed2k, sha1, ...) are for other networks
+        Ok(())
+    }
+
+    pub fn print_info(&self) {
+        for tracker in &self.trackers {
+            println!("Tracker URL: {}", tracker);
+        }
+        if let Some(hash) = self.info_hash {
+            println!("Info Hash: {}", hex::encode(hash));
+        }
+        if let Some(hash) = self.info_hash_v2 {
+            println!("Info Hash v2: {}", hex::encode(hash));
+        }
+        if let Some(name) = &self.display_name {
+            println!("Name: {}", name);
+        }
+        if let Some(length) = self.exact_length {
+            println!("Length: {}", length);
+        }
+        for peer in &self.peers {
+            println!("Peer: {}", peer);
+        }
+        for url in &self.web_seeds {
+            println!("Web Seed: {}", url);
+        }
+        if !self.select_only.is_empty() {
+            println!("Select Only: {}", format_select_only(&self.select_only));
+        }
+    }
+    pub fn info_json(&self) -> Json {
+        json!({
+            "trackers": self.trackers,
+            "info_hash": self.info_hash.map(hex::encode),
+            "info_hash_v2": self.info_hash_v2.map(hex::encode),
+            "name": self.display_name,
+            "length": self.exact_length,
+            "peers": self.peers,
+            "web_seeds": self.web_seeds,
+            "select_only": self.select_only.iter().map(|range| json!([range.start(), range.end()])).collect::<Vec<_>>(),
+        })
+    }
+    /// The info hash used on the wire: the v1 hash, or the v2 hash truncated to 20 bytes for v2-only links.
+    pub fn get_info_hash_bytes(&self) -> Vec<u8> {
+        match (self.info_hash, self.info_hash_v2) {
+            (Some(hash), _) => hash.to_vec(),
+            (None, Some(hash)) => hash[..20].to_vec(),
+            (None, None) => unreachable!("Magnet::new requires an info hash"),
+        }
+    }
+    pub fn info_hash(&self) -> Option<[u8; 20]> {
+        self.info_hash
+    }
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        self.info_hash_v2
+    }
+    pub fn trackers(&self) -> &[String] {
+        &self.trackers
+    }
+    /// Each tracker gets a tier of its own, so they're tried in the order the link lists them.
+    pub fn tracker_list(&self) -> TrackerList {
+        let tiers: Vec<Vec<String>> = self.trackers.iter().map(|tracker| vec![tracker.clone()]).collect();
+        TrackerList::new(None, &tiers)
+    }
+    /// Peers to contact directly (`x.pe`), as host:port.
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+    pub fn web_seeds(&self) -> &[String] {
+        &self.web_seeds
+    }
+    /// File indices to download (`so`); empty means all of them.
+    pub fn select_only(&self) -> &[RangeInclusive<usize>] {
+        &self.select_only
+    }
+    pub fn exact_length(&self) -> Option<u64> {
+        self.exact_length
+    }
+}
+
+fn percent_decode(name: &str, value: &str) -> Result<String, MagnetError> {
+    urlencoding::decode(value).map(|value| value.into_owned()).map_err(|_| MagnetError::Encoding(name.to_string()))
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = value.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix).then(|| &value[prefix.len()..])
+}
+
+// 40 hex characters, or 32 base32 characters as older clients wrote them.
+fn parse_btih(hash: &str) -> Option<[u8; 20]> {
+    match hash.len() {
+        40 => hex::decode(hash).ok()?.try_into().ok(),
+        32 => base32_decode(hash)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+// RFC 4648 base32 without padding, either case.
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// A multihash is the hash function's code and digest length followed by the digest; v2 uses SHA-256 (0x12, 32 bytes).
+fn parse_btmh(multihash: &str) -> Option<[u8; 32]> {
+    match hex::decode(multihash).ok()?.as_slice() {
+        [0x12, 0x20, digest @ ..] => digest.try_into().ok(),
+        _ => None,
+    }
+}
+
+// host:port, with IPv6 hosts in brackets
+fn parse_peer(peer: &str) -> Result<String, MagnetError> {
+    let invalid = || MagnetError::InvalidPeer(peer.to_string());
+    let (host, port) = peer.rsplit_once(':').ok_or_else(invalid)?;
+    let host_valid = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.strip_suffix(']').is_some_and(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok()),
+        None => !host.is_empty() && !host.contains(':'),
+    };
+    if !host_valid || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+    Ok(peer.to_string())
+}
+
+// Comma separated file indices and inclusive ranges, e.g. `0,2,4-6`.
+fn parse_select_only(list: &str) -> Result<Vec<RangeInclusive<usize>>, MagnetError> {
+    let invalid = || MagnetError::InvalidSelectOnly(list.to_string());
+    list.split(',').map(|item| {
+        let (start, end) = item.split_once('-').unwrap_or((item, item));
+        let (start, end) = (start.parse().map_err(|_| invalid())?, end.parse().map_err(|_| invalid())?);
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(start..=end)
+    }).collect()
+}
+
+fn format_select_only(ranges: &[RangeInclusive<usize>]) -> String {
+    let items: Vec<String> = ranges.iter().map(|range| if range.start() == range.end() {
+        range.start().to_string()
+    } else {
+        format!("{}-{}", range.start(), range.end())
+    }).collect();
+    items.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "d4c79217b2dc55fd95950aa2126e25efb3949769";
+
+    fn parse(params: &str) -> Result<Magnet, MagnetError> {
+        Magnet::new(&format!("magnet:?xt=urn:btih:{}{}", HASH, params))
+    }
+
+    #[test]
+    fn parses_hex_and_base32_info_hashes() {
+        let hex = parse("").unwrap();
+        assert_eq!(hex.info_hash().map(hex::encode).as_deref(), Some(HASH));
+        assert_eq!(hex.get_info_hash_bytes(), hex::decode(HASH).unwrap());
+        let base32 = Magnet::new("magnet:?xt=urn:btih:2tdzef5s3rk73fmvbkrbe3rf56zzjf3j").unwrap();
+        assert_eq!(base32.info_hash(), hex.info_hash());
+        assert!(matches!(Magnet::new("magnet:?xt=urn:btih:d4c7"), Err(MagnetError::InvalidInfoHash(_))));
+    }
+
+    #[test]
+    fn parses_v2_multihash() {
+        let digest = "ab".repeat(32);
+        let magnet = Magnet::new(&format!("magnet:?xt=urn:btmh:1220{}", digest)).unwrap();
+        assert_eq!(magnet.info_hash(), None);
+        assert_eq!(magnet.info_hash_v2().map(hex::encode), Some(digest.clone()));
+        // v2-only links use the truncated v2 hash on the wire
+        assert_eq!(magnet.get_info_hash_bytes(), vec![0xab; 20]);
+        // only SHA-256 multihashes are v2 info hashes
+        let sha1 = format!("magnet:?xt=urn:btmh:1114{}", HASH);
+        assert!(matches!(Magnet::new(&sha1), Err(MagnetError::InvalidMultihash(_))));
+    }
+
+    #[test]
+    fn rejects_missing_and_conflicting_info_hashes() {
+        assert!(matches!(Magnet::new("magnet:?dn=name"), Err(MagnetError::MissingInfoHash)));
+        assert!(matches!(Magnet::new("magnet:?xt=urn:ed2k:abc"), Err(MagnetError::MissingInfoHash)));
+        let conflicting = format!("&xt=urn:btih:{}", "00".repeat(20));
+        assert!(matches!(parse(&conflicting), Err(MagnetError::ConflictingInfoHash("v1"))));
+        // the same hash twice is fine
+        assert!(parse(&format!("&xt.1=urn:btih:{}", HASH.to_uppercase())).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_schemes_and_parameters_without_values() {
+        assert!(matches!(Magnet::new(&format!("http://example.com/?xt=urn:btih:{}", HASH)), Err(MagnetError::NotMagnet)));
+        assert!(matches!(parse("&tr"), Err(MagnetError::MissingValue(_))));
+        assert!(matches!(parse("&tr=%FF"), Err(MagnetError::Encoding(_))));
+    }
+
+    #[test]
+    fn collects_numbered_trackers_in_order() {
+        let magnet = parse("&tr.1=http%3A%2F%2Fa%2Fannounce&tr.2=udp%3A%2F%2Fb%3A80&tr=http%3A%2F%2Fc").unwrap();
+        assert_eq!(magnet.trackers(), ["http://a/announce", "udp://b:80", "http://c"]);
+        let tiers: Vec<Vec<String>> = magnet.trackers().iter().map(|tracker| vec![tracker.clone()]).collect();
+        assert_eq!(magnet.tracker_list().tiers(), tiers.as_slice());
+    }
+
+    #[test]
+    fn decodes_display_name_pluses_as_spaces() {
+        let magnet = parse("&dn=my+file%2B1.txt").unwrap();
+        assert_eq!(magnet.info_json()["name"], "my file+1.txt");
+    }
+
+    #[test]
+    fn parses_peer_addresses() {
+        let magnet = parse("&x.pe=127.0.0.1:6881&x.pe=%5B%3A%3A1%5D%3A6882&x.pe=peer.example:80").unwrap();
+        assert_eq!(magnet.peers(), ["127.0.0.1:6881", "[::1]:6882", "peer.example:80"]);
+        for invalid in ["127.0.0.1", "127.0.0.1:99999", "::1:6881", "[::1:6881", "[nope]:1", ":6881"] {
+            let param = format!("&x.pe={}", urlencoding::encode(invalid));
+            assert!(matches!(parse(&param), Err(MagnetError::InvalidPeer(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_select_only_ranges() {
+        let magnet = parse("&so=0,2,4-6&so=9").unwrap();
+        assert_eq!(magnet.select_only(), [0..=0, 2..=2, 4..=6, 9..=9]);
+        assert_eq!(format_select_only(magnet.select_only()), "0,2,4-6,9");
+        for invalid in ["", "1,", "a", "3-1", "1-2-3", "-1"] {
+            assert!(matches!(parse(&format!("&so={}", invalid)), Err(MagnetError::InvalidSelectOnly(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_exact_length_and_web_seeds() {
+        let magnet = parse("&xl=100000&ws=http%3A%2F%2Fseed%2Fmy%20dir%2F").unwrap();
+        assert_eq!(magnet.exact_length(), Some(100000));
+        assert_eq!(magnet.web_seeds(), ["http://seed/my dir/"]);
+        assert!(matches!(parse("&xl=-1"), Err(MagnetError::InvalidLength(_))));
+    }
+
+    #[test]
+    fn ignores_unknown_parameters_and_empty_pairs() {
+        let magnet = parse("&&kt=some+keywords&x.foo=bar").unwrap();
+        assert!(magnet.trackers().is_empty() && magnet.peers().is_empty());
+    }
+}
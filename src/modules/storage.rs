@@ -0,0 +1,162 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Seek, SeekFrom, Write}, path::{Component, Path, PathBuf}};
+
+use crate::modules::torrent::{FileEntry, Info};
+
+/// Writes downloaded pieces into the torrent's files, splitting each piece where it crosses a file boundary.
+pub struct Storage {
+    /// Sorted by offset, as they are laid out in the torrent.
+    files: Vec<(PathBuf, FileEntry)>,
+    piece_length: u64,
+    // the file written last, pieces usually continue where the previous one stopped
+    open: Option<(usize, File)>,
+}
+
+impl Storage {
+    /// Creates every file of the torrent at its full length. A single-file torrent is written to `output`
+    /// itself; a multi-file torrent gets a directory named after the torrent inside `output`.
+    /// Padding files (BEP 47) are never created, and symlinks are only made once all regular files
+    /// exist, so no file is ever written through a link.
+    pub fn create(info: &Info, output: &Path) -> io::Result<Self> {
+        let root = if info.is_multi_file() { output.join(relative_path([info.name()])?) } else { output.to_path_buf() };
+        if info.is_multi_file() {
+            fs::create_dir_all(&root)?;
+        }
+        let inside = Inside::new(&root, info.is_multi_file())?;
+        let mut files = vec![];
+        let mut symlinks = vec![];
+        for file in info.files() {
+            let path = if info.is_multi_file() {
+                root.join(relative_path(file.path.iter().map(String::as_str))?)
+            } else {
+                root.clone()
+            };
+            if let Some(target) = &file.symlink_path {
+                symlinks.push((path, file, target));
+                continue;
+            }
+            if !file.attributes.padding {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                    inside.check(parent)?;
+                }
+                remove_symlink(&path)?;
+                let handle = File::create(&path)?;
+                handle.set_len(file.length)?;
+                if file.attributes.executable {
+                    set_executable(&handle)?;
+                }
+            }
+            files.push((path, file.clone()));
+        }
+        for (path, file, target) in symlinks {
+            // BEP 47 symlink targets are relative to the torrent's root, the link needs them relative to itself
+            let target = relative_path(target.iter().map(String::as_str))?;
+            let depth = if info.is_multi_file() { file.path.len() - 1 } else { 0 };
+            let target: PathBuf = std::iter::repeat_n(Path::new(".."), depth).collect::<PathBuf>().join(target);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+                inside.check(parent)?;
+            }
+            remove_symlink(&path)?;
+            symlink(&target, &path)?;
+        }
+        Ok(Self { files, piece_length: info.get_piece_size() as u64, open: None })
+    }
+
+    pub fn write_piece(&mut self, piece_index: usize, piece: &[u8]) -> io::Result<()> {
+        let piece_start = piece_index as u64 * self.piece_length;
+        let piece_end = piece_start + piece.len() as u64;
+        let first = self.files.partition_point(|(_, file)| file.offset + file.length <= piece_start);
+        for index in first..self.files.len() {
+            let (path, file) = &self.files[index];
+            if file.offset >= piece_end {
+                break;
+            }
+            let file_end = file.offset + file.length;
+            if file.length == 0 {
+                continue;
+            }
+            let start = piece_start.max(file.offset);
+            let end = piece_end.min(file_end);
+            let data = &piece[(start - piece_start) as usize..(end - piece_start) as usize];
+            if file.attributes.padding {
+                if data.iter().any(|&byte| byte != 0) {
+                    let message = format!("padding file {} contains non-zero bytes", file.path.join("/"));
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+                continue;
+            }
+            let handle = match &mut self.open {
+                Some((open_index, handle)) if *open_index == index => handle,
+                open => &mut open.insert((index, OpenOptions::new().write(true).open(path)?)).1,
+            };
+            handle.seek(SeekFrom::Start(start - file.offset))?;
+            handle.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+// Joins path components from the torrent, refusing any that would escape the output directory.
+fn relative_path<'a>(components: impl IntoIterator<Item = &'a str>) -> io::Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in components {
+        let mut parsed = Path::new(component).components();
+        match (parsed.next(), parsed.next()) {
+            (Some(Component::Normal(_)), None) => path.push(component),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsafe path component {:?} in torrent", component))),
+        }
+    }
+    Ok(path)
+}
+
+// Makes sure the directories a multi-file torrent writes into really are below its root, even when
+// something on disk (a link from an earlier download, say) redirects part of the path.
+struct Inside(Option<PathBuf>);
+
+impl Inside {
+    fn new(root: &Path, multi_file: bool) -> io::Result<Self> {
+        Ok(Self(if multi_file { Some(root.canonicalize()?) } else { None }))
+    }
+
+    fn check(&self, dir: &Path) -> io::Result<()> {
+        match &self.0 {
+            Some(root) if !dir.canonicalize()?.starts_with(root) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} leads outside the download directory", dir.display())))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// Drops a link left behind by an earlier download, so nothing is written through it.
+fn remove_symlink(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn set_executable(file: &File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    // executable for everyone who can read it
+    permissions.set_mode(permissions.mode() | (permissions.mode() & 0o444) >> 2);
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, link: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("can't create symlink {}", link.display())))
+}
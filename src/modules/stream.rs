@@ -0,0 +1,346 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use thiserror::Error;
+
+use crate::modules::{bencode::{Budget, DecodeError, Limits}, value::{parse_int, BigInt, Map, Value}};
+
+// Enough for any usize; a longer string length only fits with leading zeros.
+const MAX_LENGTH_DIGITS: usize = 20;
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// One step of a bencoded document, as produced by [`StreamDecoder::next_event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Int(i64),
+    /// An integer outside the `i64` range.
+    BigInt(BigInt),
+    String(Vec<u8>),
+    ListStart,
+    DictStart,
+    /// Closes the innermost list or dictionary.
+    End,
+}
+
+enum Frame {
+    List,
+    Dict { expecting_key: bool },
+}
+
+/// Incremental bencode decoder that pulls bytes from any reader as it needs them.
+///
+/// Bytes are read one at a time, so unbuffered sources like sockets or files should be wrapped in a
+/// `BufReader`; wrap a `Take` instead when the value is followed by data the decoder must not consume.
+pub struct StreamDecoder<R> {
+    reader: R,
+    offset: usize,
+    peeked: Option<u8>,
+    stack: Vec<Frame>,
+    budget: Budget,
+}
+
+impl<R: Read> StreamDecoder<R> {
+    /// Creates a decoder that fails as soon as the input goes over `limits`, before reading or
+    /// allocating anything past them.
+    pub fn with_limits(reader: R, limits: Limits) -> Self {
+        Self { reader, offset: 0, peeked: None, stack: vec![], budget: Budget::new(limits) }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn peek_byte(&mut self) -> Result<Option<u8>, StreamError> {
+        if self.peeked.is_none() {
+            let mut byte = [0u8];
+            loop {
+                match self.reader.read(&mut byte) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            self.peeked = Some(byte[0]);
+        }
+        Ok(self.peeked)
+    }
+
+    fn next_byte(&mut self, expected: &'static str) -> Result<u8, StreamError> {
+        let byte = self.peek_byte()?.ok_or(DecodeError::UnexpectedEof { offset: self.offset, expected })?;
+        self.peeked = None;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    // Reads the bytes up to `terminator`, which is consumed. A string length must be all digits; an
+    // integer is read whole and checked by the caller, like the slice decoder does. Fails with `limit`
+    // at `start` once there are more than `max_digits`, so an endless run of digits can't grow the buffer.
+    fn read_number(&mut self, start: usize, terminator: u8, expected: &'static str, max_digits: usize, limit: &'static str) -> Result<Vec<u8>, StreamError> {
+        let mut digits = vec![];
+        loop {
+            let offset = self.offset;
+            let byte = self.next_byte(expected)?;
+            if byte == terminator {
+                return Ok(digits);
+            }
+            if terminator == b':' && !byte.is_ascii_digit() {
+                return Err(DecodeError::UnexpectedByte { offset, found: byte, expected }.into());
+            }
+            if digits.len() == max_digits {
+                return Err(DecodeError::LimitExceeded { offset: start, limit }.into());
+            }
+            digits.push(byte);
+        }
+    }
+
+    /// Returns the next event, or `None` once the reader is exhausted between top-level values.
+    pub fn next_event(&mut self) -> Result<Option<Event>, StreamError> {
+        let start = self.offset;
+        let Some(first) = self.peek_byte()? else {
+            if self.stack.is_empty() {
+                return Ok(None);
+            }
+            return Err(DecodeError::UnexpectedEof { offset: start, expected: "a value or 'e'" }.into());
+        };
+        match self.stack.last_mut() {
+            Some(Frame::Dict { expecting_key: true }) if first != b'e' && !first.is_ascii_digit() => {
+                return Err(DecodeError::NonStringKey { offset: start }.into());
+            },
+            Some(Frame::Dict { expecting_key: false }) if first == b'e' => {
+                return Err(DecodeError::UnexpectedByte { offset: start, found: first, expected: "a dictionary value" }.into());
+            },
+            Some(Frame::Dict { expecting_key }) => *expecting_key = !*expecting_key,
+            _ => {},
+        }
+        if first != b'e' {
+            self.budget.element(start)?;
+        }
+        let event = match first {
+            b'0'..=b'9' => {
+                let length = self.read_number(start, b':', "':' after string length", MAX_LENGTH_DIGITS, "string length")?;
+                let length = std::str::from_utf8(&length).ok()
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .ok_or(DecodeError::InvalidLength { offset: start })?;
+                self.budget.string(start, length)?;
+                let mut string = vec![];
+                (&mut self.reader).take(length as u64).read_to_end(&mut string)?;
+                self.offset += string.len();
+                if string.len() < length {
+                    return Err(DecodeError::UnexpectedEof { offset: self.offset, expected: "string contents" }.into());
+                }
+                Event::String(string)
+            },
+            b'i' => {
+                self.next_byte("an integer")?;
+                let digits = self.read_number(start, b'e', "'e' after integer", self.budget.max_integer_digits(), "integer length")?;
+                match parse_int(&digits).ok_or(DecodeError::InvalidInteger { offset: start })? {
+                    Ok(number) => Event::Int(number),
+                    Err(big) => Event::BigInt(big),
+                }
+            },
+            b'l' => {
+                self.budget.enter(start)?;
+                self.next_byte("a list")?;
+                self.stack.push(Frame::List);
+                Event::ListStart
+            },
+            b'd' => {
+                self.budget.enter(start)?;
+                self.next_byte("a dictionary")?;
+                self.stack.push(Frame::Dict { expecting_key: true });
+                Event::DictStart
+            },
+            b'e' if !self.stack.is_empty() => {
+                self.next_byte("'e'")?;
+                self.stack.pop();
+                self.budget.leave();
+                Event::End
+            },
+            _ => return Err(DecodeError::UnexpectedByte { offset: start, found: first, expected: "a string length, 'i', 'l' or 'd'" }.into()),
+        };
+        Ok(Some(event))
+    }
+
+    /// Reads one complete value, or `None` if the reader is exhausted before it starts.
+    pub fn read_value(&mut self) -> Result<Option<Value>, StreamError> {
+        let Some(event) = self.next_event()? else {
+            return Ok(None);
+        };
+        self.build_value(event).map(Some)
+    }
+
+    fn build_value(&mut self, event: Event) -> Result<Value, StreamError> {
+        match event {
+            Event::Int(i) => Ok(Value::Int(i)),
+            Event::BigInt(i) => Ok(Value::BigInt(i)),
+            Event::String(s) => Ok(Value::String(s)),
+            Event::ListStart => {
+                let mut list = vec![];
+                loop {
+                    match self.require_event()? {
+                        Event::End => return Ok(Value::List(list)),
+                        event => list.push(self.build_value(event)?),
+                    }
+                }
+            },
+            Event::DictStart => {
+                let mut map = Map::new();
+                loop {
+                    match self.require_event()? {
+                        Event::End => return Ok(Value::Map(map)),
+                        Event::String(key) => {
+                            let event = self.require_event()?;
+                            map.insert(key, self.build_value(event)?);
+                        },
+                        _ => unreachable!("next_event only yields strings in key position"),
+                    }
+                }
+            },
+            Event::End => unreachable!("End is only produced inside a container"),
+        }
+    }
+
+    fn require_event(&mut self) -> Result<Event, StreamError> {
+        let offset = self.offset;
+        self.next_event()?.ok_or(StreamError::Decode(DecodeError::UnexpectedEof { offset, expected: "a value or 'e'" }))
+    }
+}
+
+/// Writes `value` as bencode straight to `writer`, without building intermediate buffers.
+pub fn write_value<W: Write + ?Sized>(value: &Value, writer: &mut W) -> io::Result<()> {
+    match value {
+        Value::Int(val) => write!(writer, "i{}e", val),
+        Value::BigInt(val) => write!(writer, "i{}e", val),
+        Value::String(val) => {
+            write!(writer, "{}:", val.len())?;
+            writer.write_all(val)
+        },
+        Value::List(list) => {
+            writer.write_all(b"l")?;
+            for value in list {
+                write_value(value, writer)?;
+            }
+            writer.write_all(b"e")
+        },
+        Value::Map(map) => {
+            writer.write_all(b"d")?;
+            for (key, val) in map.iter() {
+                write!(writer, "{}:", key.len())?;
+                writer.write_all(key)?;
+                write_value(val, writer)?;
+            }
+            writer.write_all(b"e")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::repeat;
+
+    use super::*;
+    use crate::modules::bencode::decode_bencoded_value;
+
+    // Hands out one byte per read, like a slow socket.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    fn stream_decode(input: &[u8]) -> Result<Value, DecodeError> {
+        match StreamDecoder::with_limits(Trickle(input), Limits::default()).read_value() {
+            Ok(value) => Ok(value.expect("the inputs aren't empty")),
+            Err(StreamError::Decode(err)) => Err(err),
+            Err(err) => panic!("unexpected {}", err),
+        }
+    }
+
+    #[test]
+    fn decodes_like_the_slice_decoder() {
+        let inputs: [&[u8]; 15] = [
+            b"i42e", b"i-170141183460469231731687303715884105728e", b"0:", b"4:spam", b"le", b"de",
+            b"d8:announce3:url4:infod6:lengthi10e4:name1:x5:filesll1:aeeee", b"l1:ad1:bli1ei2eeee",
+            b"5:ab", b"i12", b"d3:fooe", b"di1ei2ee", b"li1ei--3ee", b"i1xe", b"x",
+        ];
+        for input in inputs {
+            let sliced = decode_bencoded_value(input).map(|(value, _)| value);
+            assert_eq!(stream_decode(input), sliced, "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn yields_events_in_document_order() {
+        let mut decoder = StreamDecoder::with_limits(&b"d1:ali1ei-2ee1:bdee"[..], Limits::default());
+        let mut events = vec![];
+        while let Some(event) = decoder.next_event().unwrap() {
+            events.push(event);
+        }
+        assert_eq!(events, [
+            Event::DictStart, Event::String(b"a".to_vec()), Event::ListStart, Event::Int(1), Event::Int(-2), Event::End,
+            Event::String(b"b".to_vec()), Event::DictStart, Event::End, Event::End,
+        ]);
+    }
+
+    #[test]
+    fn reads_consecutive_values_and_stops_at_the_end() {
+        let mut decoder = StreamDecoder::with_limits(&b"i1e3:abcle"[..], Limits::default());
+        assert_eq!(decoder.read_value().unwrap(), Some(Value::Int(1)));
+        assert_eq!(decoder.read_value().unwrap(), Some(Value::String(b"abc".to_vec())));
+        assert_eq!(decoder.read_value().unwrap(), Some(Value::List(vec![])));
+        assert_eq!(decoder.read_value().unwrap(), None);
+    }
+
+    #[test]
+    fn leaves_what_follows_a_value_in_the_reader() {
+        let input = b"d1:ai1ee\x00\x01rest";
+        let mut decoder = StreamDecoder::with_limits((&input[..]).take(8), Limits::default());
+        assert!(decoder.read_value().unwrap().is_some());
+        let mut rest = vec![];
+        decoder.into_inner().into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"\x00\x01rest");
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let input = b"d4:infod6:lengthi10e4:name1:xe4:listli-1ei99999999999999999999e0:ee";
+        let value = stream_decode(input).unwrap();
+        let mut written = vec![];
+        write_value(&value, &mut written).unwrap();
+        assert_eq!(written, input);
+    }
+
+    fn limit(reader: impl Read, limits: Limits) -> (usize, &'static str) {
+        match StreamDecoder::with_limits(reader, limits).read_value() {
+            Err(StreamError::Decode(DecodeError::LimitExceeded { offset, limit })) => (offset, limit),
+            other => panic!("expected a limit error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stops_reading_an_endless_string_length() {
+        assert_eq!(limit(repeat(b'1'), Limits::default()), (0, "string length"));
+        assert_eq!(limit(b"l".chain(repeat(b'0')), Limits::default()), (1, "string length"));
+    }
+
+    #[test]
+    fn stops_reading_an_endless_integer() {
+        let limits = Limits { max_string_len: 64, ..Limits::network() };
+        assert_eq!(limit(b"i".chain(repeat(b'9')), limits), (0, "integer length"));
+        assert_eq!(limit(b"li-".chain(repeat(b'9')), limits), (1, "integer length"));
+    }
+}
@@ -1,9 +1,11 @@
-use std::{io::{Read, Write}, net::TcpStream};
+use std::{collections::{BTreeMap, HashSet}, io::{self, BufReader, Read, Write}, ops::Range};
 
 use hex::decode;
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value as Json};
 use sha1::{Digest, Sha1};
 
-use crate::{generate_random_string, modules::{bencode::{decode_bencoded_value, encode_value}, helpers::{get_handshake, get_peers}, value::{Map, Value}}};
+use crate::{bencode, generate_random_string, modules::{bencode::{decode_bencoded_value, decode_with_limits, encode_value, Limits}, helpers::{connect, get_handshake, ExtensionHandshake}, magnet::Magnet, stream::{write_value, StreamDecoder}, tracker::TrackerList, v2::{info_hash_v2_hex, parse_file_tree, verify_piece_layer, V2File, BLOCK_SIZE}, value::{Map, Value, ValueRef}, value_serde::{from_value, from_value_ref, to_value, Error}}};
 
 fn get_pieces_hashes(input: &[u8]) -> Vec<[u8; 20]> {
     let mut i = 0;
@@ -16,32 +18,204 @@ fn get_pieces_hashes(input: &[u8]) -> Vec<[u8; 20]> {
     output
 }
 
+/// SHA-1 of the info dictionary's encoded bytes, as lowercase hex.
+pub fn info_hash_hex(raw_info: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(raw_info);
+    format!("{:x}", hasher.finalize())
+}
+
+/// One file of the torrent's content, laid out back to back with the others in piece space.
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    /// Path components below the torrent's root directory; a single-file torrent has just its name.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Position of the file's first byte within the concatenated content.
+    pub offset: u64,
+    pub attributes: FileAttributes,
+    /// Where a symlink points, as path components below the torrent's root directory.
+    pub symlink_path: Option<Vec<String>>,
+}
+
+/// The flags in a BEP 47 `attr` string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// `p`: filler that aligns the next file to a piece boundary, all zeros and never written to disk.
+    pub padding: bool,
+    /// `x`
+    pub executable: bool,
+    /// `h`
+    pub hidden: bool,
+    /// `l`: the file is a symlink to its `symlink path`.
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Unknown flags are ignored, as BEP 47 asks.
+    pub fn parse(attr: &str) -> Self {
+        Self {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
 pub struct Info {
-    length: i64,
-    _name: String,
+    length: u64,
+    name: String,
     piece_length: i64,
+    /// v1 piece hashes, empty for v2-only torrents.
     pieces: Vec<[u8; 20]>,
-    hash: String,
+    files: Vec<FileEntry>,
+    v2_files: Vec<V2File>,
+    multi_file: bool,
+    private: bool,
+    source: Option<String>,
+    hash: Option<String>,
+    hash_v2: Option<String>,
+    /// The dictionary as it was encoded, so it can be written back out with the same info hash.
+    raw: Vec<u8>,
+    canonical: bool,
+}
+
+#[derive(Deserialize)]
+struct InfoDict {
+    length: Option<u64>,
+    files: Option<Vec<FileDict>>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: i64,
+    pieces: Option<serde_bytes::ByteBuf>,
+    #[serde(rename = "meta version")]
+    meta_version: Option<i64>,
+    #[serde(rename = "file tree")]
+    file_tree: Option<Value>,
+    #[serde(default, deserialize_with = "lenient_flag")]
+    private: bool,
+    #[serde(default, deserialize_with = "lossy_string")]
+    source: Option<String>,
+    // a single-file torrent keeps its file's attributes in the info dictionary itself
+    attr: Option<String>,
+    #[serde(rename = "symlink path")]
+    symlink_path: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct FileDict {
+    length: u64,
+    path: Vec<String>,
+    attr: Option<String>,
+    #[serde(rename = "symlink path")]
+    symlink_path: Option<Vec<String>>,
 }
 
-#[allow(dead_code)]
 impl Info {
-    fn new(val: Value) -> Option<Self> {
-        if let Value::Map(info_map) = val {
-            let length = info_map.get("length")?.get_int()?;
-            let name = String::from_utf8(info_map.get("name")?.get_string()?).unwrap();
-            let piece_length = info_map.get("piece length")?.get_int()?;
-            let pieces_raw = info_map.get("pieces")?.get_string()?;
-            let pieces = get_pieces_hashes(&pieces_raw);
-            
-            let bencoded_info_map = encode_value(Value::Map(info_map));
-            let mut hasher = Sha1::new();
-            hasher.update(bencoded_info_map);
-            let sha1_hash = hasher.finalize();
-            let sha1_hash_hex = format!("{:x}", sha1_hash);
-            return Some(Self {length, _name: name, piece_length, pieces, hash: sha1_hash_hex})
-        }
-        None
+    // `raw` is the info dictionary exactly as it appeared in the input; the info hash is taken over
+    // those bytes because re-encoding a non-canonical dictionary would produce a different hash.
+    fn new(val: Value, raw: &[u8]) -> Result<Self, Error> {
+        let mut bencoded_info_map = vec![];
+        write_value(&val, &mut bencoded_info_map).expect("Writing to a Vec can't fail");
+        let canonical = bencoded_info_map == raw;
+        let info_dict: InfoDict = from_value(val)?;
+        let v2 = match info_dict.meta_version {
+            None => false,
+            Some(2) => true,
+            Some(version) => return Err(<Error as de::Error>::custom(format!("unsupported version {}", version)).prefixed("meta version")),
+        };
+        if info_dict.pieces.is_none() && !v2 {
+            return Err(de::Error::custom("missing field `pieces`"));
+        }
+        if info_dict.pieces.as_ref().is_some_and(|pieces| pieces.len() % 20 != 0) {
+            return Err(<Error as de::Error>::custom("must be a whole number of 20-byte hashes").prefixed("pieces"));
+        }
+        let pieces = info_dict.pieces.as_ref().map(|pieces| get_pieces_hashes(pieces)).unwrap_or_default();
+
+        let v2_files = match &info_dict.file_tree {
+            Some(Value::Map(tree)) if v2 => parse_file_tree(tree)?,
+            Some(_) if v2 => return Err(<Error as de::Error>::custom("expected a dictionary").prefixed("file tree")),
+            None if v2 => return Err(de::Error::custom("missing field `file tree`")),
+            _ => vec![],
+        };
+        if info_dict.piece_length <= 0 {
+            return Err(<Error as de::Error>::custom("must be positive").prefixed("piece length"));
+        }
+        if v2 && (info_dict.piece_length < BLOCK_SIZE as i64 || !(info_dict.piece_length as u64).is_power_of_two()) {
+            return Err(<Error as de::Error>::custom("v2 torrents need a power of two of at least 16 KiB").prefixed("piece length"));
+        }
+
+        let (file_dicts, multi_file) = match (info_dict.length, info_dict.files) {
+            // v2-only torrents describe their files in the file tree alone
+            (None, None) if info_dict.pieces.is_none() => {
+                let single = matches!(v2_files.as_slice(), [file] if file.path == [info_dict.name.clone()]);
+                let files = v2_files.iter().map(|file| FileDict {
+                    length: file.length,
+                    path: file.path.clone(),
+                    attr: file.attr.clone(),
+                    symlink_path: file.symlink_path.clone(),
+                });
+                (files.collect(), !single)
+            },
+            (Some(length), None) => {
+                let file = FileDict { length, path: vec![info_dict.name.clone()], attr: info_dict.attr, symlink_path: info_dict.symlink_path };
+                (vec![file], false)
+            },
+            (None, Some(files)) if !files.is_empty() => (files, true),
+            (None, Some(_)) => return Err(de::Error::custom("files: a multi-file torrent needs at least one file")),
+            (Some(_), Some(_)) => return Err(de::Error::custom("only one of length and files may be present")),
+            (None, None) => return Err(de::Error::custom("missing field `length` or `files`")),
+        };
+        let mut files = vec![];
+        let mut offset = 0u64;
+        for (i, file) in file_dicts.into_iter().enumerate() {
+            if file.path.is_empty() {
+                return Err(<Error as de::Error>::custom("path can't be empty").prefixed(format!("[{}]", i)).prefixed("files"));
+            }
+            let attributes = FileAttributes::parse(file.attr.as_deref().unwrap_or_default());
+            let symlink_path = match file.symlink_path {
+                Some(target) if attributes.symlink && !target.is_empty() => Some(target),
+                _ if attributes.symlink => {
+                    return Err(<Error as de::Error>::custom("symlink without a `symlink path`").prefixed(format!("[{}]", i)).prefixed("files"));
+                },
+                _ => None,
+            };
+            if symlink_path.is_some() && file.length > 0 {
+                return Err(<Error as de::Error>::custom("a symlink can't have a length").prefixed(format!("[{}]", i)).prefixed("files"));
+            }
+            files.push(FileEntry { path: file.path, length: file.length, offset, attributes, symlink_path });
+            offset = offset.checked_add(file.length).ok_or_else(|| <Error as de::Error>::custom("total length overflows"))?;
+        }
+        // a file below another file's path would be written through it, and through a symlink that can be
+        // anywhere on disk
+        let paths: HashSet<&[String]> = files.iter().map(|file| file.path.as_slice()).collect();
+        if let Some(file) = files.iter().find(|file| (1..file.path.len()).any(|len| paths.contains(&file.path[..len]))) {
+            return Err(<Error as de::Error>::custom(format!("{} is inside a path that is also a file", file.path.join("/"))).prefixed("files"));
+        }
+        // every piece the files span needs a hash, and a hash past the end would have no data to check
+        let expected_pieces = offset.div_ceil(info_dict.piece_length as u64);
+        if info_dict.pieces.is_some() && pieces.len() as u64 != expected_pieces {
+            return Err(<Error as de::Error>::custom(format!(
+                "{} bytes in pieces of {} need {} hashes, found {}", offset, info_dict.piece_length, expected_pieces, pieces.len()
+            )).prefixed("pieces"));
+        }
+
+        Ok(Self {
+            length: offset,
+            name: info_dict.name,
+            piece_length: info_dict.piece_length,
+            hash: info_dict.pieces.is_some().then(|| info_hash_hex(raw)),
+            hash_v2: v2.then(|| info_hash_v2_hex(raw)),
+            pieces,
+            files,
+            v2_files,
+            multi_file,
+            private: info_dict.private,
+            source: info_dict.source,
+            raw: raw.to_vec(),
+            canonical,
+        })
     }
     fn print_piece_hashes(&self) {
         for piece in &self.pieces {
@@ -52,98 +226,404 @@ impl Info {
             println!("{}", piece_hash);
         }
     }
+    /// The info hash used on the wire and with trackers: the SHA-1 hash, or for v2-only torrents the
+    /// SHA-256 hash truncated to 20 bytes.
     pub fn get_info_hash(&self) -> String {
-        self.hash.clone()
+        hex::encode(self.get_info_hash_bytes())
     }
     pub fn get_info_hash_bytes(&self) -> Vec<u8> {
-        let hex_str = &self.hash;
-        decode(hex_str).unwrap()
+        match (&self.hash, self.get_info_hash_v2_truncated()) {
+            (Some(hash), _) => decode(hash).unwrap(),
+            (None, Some(truncated)) => truncated.to_vec(),
+            (None, None) => unreachable!("Info::new requires v1 pieces or a v2 file tree"),
+        }
+    }
+    pub fn get_info_hash_v2_truncated(&self) -> Option<[u8; 20]> {
+        let hash = decode(self.hash_v2.as_ref()?).unwrap();
+        Some(hash[..20].try_into().unwrap())
+    }
+    pub fn supports_v1(&self) -> bool {
+        self.hash.is_some()
+    }
+    pub fn supports_v2(&self) -> bool {
+        self.hash_v2.is_some()
     }
-    pub fn get_piece(&self, piece_index: usize) -> [u8; 20] {
-        let piece = self.pieces[piece_index];
-        piece
+    pub fn v2_files(&self) -> &[V2File] {
+        &self.v2_files
     }
     pub fn get_piece_size(&self) -> usize {
         self.piece_length as usize
     }
+    /// Total length of the content, summed over every file.
     pub fn get_file_size(&self) -> usize {
         self.length as usize
     }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+    pub fn is_multi_file(&self) -> bool {
+        self.multi_file
+    }
+    // Prints each file under the directories leading to it, only opening a directory again when the
+    // previous file wasn't in it.
+    fn print_file_tree(&self) {
+        println!("{}/", self.name);
+        let mut open_dirs: &[String] = &[];
+        for file in self.files.iter().filter(|file| !file.attributes.padding) {
+            let (file_name, dirs) = file.path.split_last().expect("file paths are never empty");
+            let shared = open_dirs.iter().zip(dirs).take_while(|(a, b)| a == b).count();
+            for (depth, dir) in dirs.iter().enumerate().skip(shared) {
+                println!("{}{}/", "  ".repeat(depth + 1), dir);
+            }
+            let indent = "  ".repeat(dirs.len() + 1);
+            match &file.symlink_path {
+                Some(target) => println!("{}{} -> {}", indent, file_name, target.join("/")),
+                None if file.attributes.executable => println!("{}{}* ({} bytes)", indent, file_name, file.length),
+                None => println!("{}{} ({} bytes)", indent, file_name, file.length),
+            }
+            open_dirs = dirs;
+        }
+    }
     pub fn total_pieces(&self) -> usize {
         self.pieces.len()
     }
+    /// Where the piece lies in the concatenated content; only the last piece can be shorter. None for
+    /// an index past the last piece.
+    pub fn piece_range(&self, piece_index: usize) -> Option<Range<u64>> {
+        if piece_index >= self.total_pieces() {
+            return None;
+        }
+        let start = piece_index as u64 * self.piece_length as u64;
+        Some(start..(start + self.piece_length as u64).min(self.length))
+    }
+    /// Checks downloaded piece data against the piece's SHA-1 hash.
+    pub fn verify_piece(&self, piece_index: usize, piece: &[u8]) -> bool {
+        self.pieces.get(piece_index).is_some_and(|hash| Sha1::digest(piece).as_slice() == hash)
+    }
+    /// The info dictionary with every key it was loaded with, including ones this crate doesn't know.
+    pub fn to_value(&self) -> Value {
+        let (value, _) = decode_bencoded_value(&self.raw).expect("the info dictionary was decoded before");
+        value
+    }
+    /// The exact bytes the info hash is computed over.
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+    /// Whether the dictionary was canonically encoded. If not, re-encoding it would change the info hash.
+    pub fn is_canonical(&self) -> bool {
+        self.canonical
+    }
 }
 
 pub struct Torrent {
-    announce: String,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    encoding: Option<String>,
+    url_list: Vec<String>,
+    httpseeds: Vec<String>,
+    nodes: Vec<(String, u16)>,
+    piece_layers: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Top-level keys not covered by the fields above, kept so they survive `to_value`.
+    extra: Map,
+    pub trackers: TrackerList,
     pub info: Info,
 }
 
+// Top-level keys `Torrent` has a field for; everything else ends up in `extra`.
+const KNOWN_KEYS: [&str; 11] = [
+    "announce", "announce-list", "comment", "created by", "creation date", "encoding",
+    "url-list", "httpseeds", "nodes", "piece layers", "info",
+];
+
+#[derive(Deserialize)]
+struct MetaInfo {
+    announce: Option<String>,
+    #[serde(rename = "announce-list", default)]
+    announce_list: Vec<Vec<String>>,
+    #[serde(default, deserialize_with = "lossy_string")]
+    comment: Option<String>,
+    #[serde(rename = "created by", default, deserialize_with = "lossy_string")]
+    created_by: Option<String>,
+    #[serde(rename = "creation date", default, deserialize_with = "lenient")]
+    creation_date: Option<i64>,
+    #[serde(default, deserialize_with = "lossy_string")]
+    encoding: Option<String>,
+    #[serde(rename = "url-list", default, deserialize_with = "lenient")]
+    url_list: Option<OneOrMany>,
+    #[serde(default, deserialize_with = "lenient_list")]
+    httpseeds: Vec<String>,
+    #[serde(default, deserialize_with = "lenient_list")]
+    nodes: Vec<(String, u16)>,
+    #[serde(rename = "piece layers", default)]
+    piece_layers: BTreeMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf>,
+}
+
+// `url-list` (BEP 19) is a single URL or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Vec<String> {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(url) => vec![url],
+            OneOrMany::Many(urls) => urls,
+        }
+    }
+}
+
+// The optional fields below are informational and often sloppy in the wild (a `comment` in the
+// `encoding` charset, a `creation date` string), so they're read leniently instead of failing the torrent.
+
+fn lossy_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(bytes) => Some(String::from_utf8_lossy(&bytes).into_owned()),
+        _ => None,
+    })
+}
+
+fn lenient<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Ok(from_value(Value::deserialize(deserializer)?).ok())
+}
+
+// Keeps the entries that parse and drops the rest.
+fn lenient_list<'de, D: Deserializer<'de>, T: DeserializeOwned>(deserializer: D) -> Result<Vec<T>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::List(items) => items.into_iter().filter_map(|item| from_value(item).ok()).collect(),
+        _ => vec![],
+    })
+}
+
+// BEP 27 uses 1, but clients treat any non-zero integer as set.
+fn lenient_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Int(flag) => flag != 0,
+        Value::BigInt(_) => true,
+        _ => false,
+    })
+}
+
+// The top-level fields of `Torrent` as they're written back out. Unset and empty fields are left out.
+#[derive(Serialize)]
+struct KnownFields<'a> {
+    announce: Option<&'a str>,
+    #[serde(rename = "announce-list", skip_serializing_if = "<[_]>::is_empty")]
+    announce_list: &'a [Vec<String>],
+    comment: Option<&'a str>,
+    #[serde(rename = "created by")]
+    created_by: Option<&'a str>,
+    #[serde(rename = "creation date")]
+    creation_date: Option<i64>,
+    encoding: Option<&'a str>,
+    #[serde(rename = "url-list", skip_serializing_if = "<[_]>::is_empty")]
+    url_list: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    httpseeds: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    nodes: &'a [(String, u16)],
+    #[serde(rename = "piece layers", skip_serializing_if = "BTreeMap::is_empty")]
+    piece_layers: BTreeMap<&'a serde_bytes::Bytes, &'a serde_bytes::Bytes>,
+}
+
+/// Encodes a metainfo dictionary with `raw_info` written verbatim in place of its `info` value.
+fn encode_with_raw_info(map: &Map, raw_info: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![b'd'];
+    for (key, value) in map {
+        write!(bytes, "{}:", key.len()).expect("Writing to a Vec can't fail");
+        bytes.extend(key);
+        if key == b"info" {
+            bytes.extend(raw_info);
+        } else {
+            write_value(value, &mut bytes).expect("Writing to a Vec can't fail");
+        }
+    }
+    bytes.push(b'e');
+    bytes
+}
+
+// Example: 1700000000 -> "2023-11-14 22:13:20 UTC"
+fn format_unix_time(timestamp: i64) -> String {
+    let (days, seconds) = (timestamp.div_euclid(86400), timestamp.rem_euclid(86400));
+    // civil-from-days, counting 400-year eras from 0000-03-01
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
 impl Torrent {
-    pub fn new(val: Value) -> Option<Self> {
-        let torrent_map = val.get_map()?;
-        let announce = String::from_utf8(torrent_map.get("announce")?.get_string()?).unwrap();
-        let info = Info::new(torrent_map.get("info")?)?;
-        Some(Self { announce, info })
+    pub fn new(val: &ValueRef) -> Result<Self, Error> {
+        let ValueRef::Map(torrent_map) = val else {
+            return Err(de::Error::custom("a torrent must be a dictionary"));
+        };
+        let meta_info: MetaInfo = from_value_ref(val)?;
+        let (Some(info), Some(raw_info)) = (torrent_map.get("info"), torrent_map.get_raw("info")) else {
+            return Err(de::Error::missing_field("info"));
+        };
+        let info = Info::new(info.to_owned_value(), raw_info).map_err(|err| err.prefixed("info"))?;
+        let map = torrent_map.iter().filter(|(key, _)| *key != b"info").map(|(key, value)| (key, value.to_owned_value())).collect();
+        Self::from_parts(meta_info, map, info)
+    }
+    /// Replaces the top-level metadata with `map`, read the same way as by [`Torrent::new`]. The info
+    /// dictionary is kept, whatever `map` has under `info`.
+    pub fn with_metadata(self, mut map: Map) -> Result<Self, Error> {
+        map.remove("info");
+        let meta_info: MetaInfo = from_value(Value::Map(map.clone()))?;
+        Self::from_parts(meta_info, map, self.info)
+    }
+    // `map` holds the top-level keys other than `info` that `meta_info` was read from.
+    fn from_parts(meta_info: MetaInfo, map: Map, info: Info) -> Result<Self, Error> {
+        let (mut known_map, mut extra) = (Map::new(), Map::new());
+        for (key, value) in map {
+            if KNOWN_KEYS.iter().any(|known| known.as_bytes() == key) {
+                known_map.insert(key, value);
+            } else {
+                extra.insert(key, value);
+            }
+        }
+        let piece_layers: BTreeMap<Vec<u8>, Vec<u8>> = meta_info.piece_layers.into_iter()
+            .map(|(root, layer)| (root.into_vec(), layer.into_vec()))
+            .collect();
+        // files that fit in one piece have no layer, their pieces root already is the piece hash
+        for file in info.v2_files().iter().filter(|file| file.length > info.get_piece_size() as u64) {
+            let root = file.pieces_root.expect("non-empty files have a pieces root");
+            let layer = piece_layers.get(root.as_slice())
+                .ok_or_else(|| <Error as de::Error>::custom(format!("missing the layer for {}", file.path.join("/"))))
+                .map_err(|err| err.prefixed("piece layers"))?;
+            verify_piece_layer(file, info.get_piece_size() as u64, layer)
+                .map_err(|message| <Error as de::Error>::custom(format!("layer for {}: {}", file.path.join("/"), message)).prefixed("piece layers"))?;
+        }
+        let announce_list: Vec<Vec<String>> = meta_info.announce_list.into_iter().filter(|tier| !tier.is_empty()).collect();
+        let trackers = TrackerList::new(meta_info.announce.as_deref(), &announce_list);
+        let mut torrent = Self {
+            announce: meta_info.announce,
+            announce_list,
+            comment: meta_info.comment,
+            created_by: meta_info.created_by,
+            creation_date: meta_info.creation_date,
+            encoding: meta_info.encoding,
+            url_list: meta_info.url_list.map(Vec::from).unwrap_or_default(),
+            httpseeds: meta_info.httpseeds,
+            nodes: meta_info.nodes,
+            piece_layers,
+            extra,
+            trackers,
+            info,
+        };
+        // fields that were read leniently keep their original value, so `to_value` writes them back unchanged
+        let known = torrent.known_fields();
+        for (key, original) in known_map {
+            if known.get_bytes(&key) != Some(&original) {
+                torrent.extra.insert(key, original);
+            }
+        }
+        Ok(torrent)
     }
-    pub fn from_magnet(magnet: Magnet) -> Option<Self> {
+    /// Fetches the metadata a magnet link points to from its peers: the link's own `x.pe` peers first,
+    /// then whatever the trackers know about. Peers that fail are skipped with a warning.
+    pub fn from_magnet(magnet: &Magnet) -> Option<Self> {
         let my_id = generate_random_string(20);
         let info_hash = magnet.get_info_hash_bytes();
-        let handshake = get_handshake(&info_hash, &my_id, true);
-        let peers = get_peers(&magnet.get_url().unwrap(), &info_hash, &my_id, 999);
-        let peer = format!("{}:{}", peers[0].0, peers[0].1);
-
-        let mut stream = TcpStream::connect(peer).expect("Failed to connect");
-        stream.write_all(&handshake).expect("Failed to write to stream");
+        let mut trackers = magnet.tracker_list();
+        let mut peers = magnet.peers().to_vec();
+        if !trackers.tiers().is_empty() {
+            match trackers.get_peers(&info_hash, &my_id, 999) {
+                Ok(found) => peers.extend(found.iter().map(|(ip, port)| format!("{}:{}", ip, port))),
+                Err(err) => eprintln!("Warning: {}", err),
+            }
+        }
+        let info = peers.iter().find_map(|peer| {
+            let info = Self::metadata_from_peer(magnet, &my_id, peer);
+            if info.is_none() {
+                eprintln!("Warning: peer {}: couldn't get the metadata", peer);
+            }
+            info
+        })?;
+        let announce_list = match magnet.trackers() {
+            [_, _, ..] => magnet.trackers().iter().map(|tracker| vec![tracker.clone()]).collect(),
+            _ => vec![],
+        };
+        Some(Self {
+            announce: magnet.trackers().first().cloned(),
+            announce_list,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            url_list: magnet.web_seeds().to_vec(),
+            httpseeds: vec![],
+            nodes: vec![],
+            piece_layers: BTreeMap::new(),
+            extra: Map::new(),
+            trackers,
+            info,
+        })
+    }
+    // Downloads and checks the info dictionary from a single peer with the ut_metadata extension (BEP 9).
+    fn metadata_from_peer(magnet: &Magnet, my_id: &str, peer: &str) -> Option<Info> {
+        let handshake = get_handshake(&magnet.get_info_hash_bytes(), my_id, true);
+        let mut stream = connect(peer).ok()?;
+        stream.write_all(&handshake).ok()?;
 
         let mut buffer = [0; 1024];
-        stream.read_exact(&mut buffer[0..1]).expect("Failed to read from stream");
+        stream.read_exact(&mut buffer[0..1]).ok()?;
         let protocol_length = buffer[0] as usize;
-        stream.read_exact(&mut buffer[1..1+protocol_length+8+20+20]).unwrap();
+        stream.read_exact(&mut buffer[1..1+protocol_length+8+20+20]).ok()?;
         let has_extension_support = buffer[1+protocol_length+5] & 16u8 > 0;
 
-        // wait for bitfield
-        stream.read_exact(&mut buffer[..4]).expect("Failed to read from stream");
+        // wait for bitfield, its contents don't matter here
+        stream.read_exact(&mut buffer[..5]).ok()?;
         let length = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-        stream.read_exact(&mut buffer[4..4+length as usize]).unwrap();
         let message_type = buffer[4];
 
-        if message_type != 5 || !has_extension_support {
+        if length == 0 || message_type != 5 || !has_extension_support {
             return None
         }
+        io::copy(&mut (&mut stream).take(length as u64 - 1), &mut io::sink()).ok()?;
 
         //extension handshake
-        let mut inner_dict = Map::new();
-        let my_metadata_ext_id = 2;
-        inner_dict.insert("ut_metadata".as_bytes().to_vec(), Value::Int(my_metadata_ext_id));
-        let mut outer_dict = Map::new();
-        outer_dict.insert("m".as_bytes().to_vec(), Value::Map(inner_dict));
-        let bencoded_value = encode_value(Value::Map(outer_dict));
+        let my_metadata_ext_id: u8 = 2;
+        let bencoded_value = encode_value(bencode!({ "m": { "ut_metadata": my_metadata_ext_id } }));
         let mut extension_handshake = vec![];
         extension_handshake.extend((bencoded_value.len() as u32 + 2).to_be_bytes());
         extension_handshake.push(20);
         extension_handshake.push(0);
         extension_handshake.extend(bencoded_value);
-        stream.write_all(&extension_handshake).expect("Couldn't write to stream");
+        stream.write_all(&extension_handshake).ok()?;
 
-        stream.read_exact(&mut buffer[0..4]).expect("Couldn't read from stream");
+        stream.read_exact(&mut buffer[0..6]).ok()?;
         let length = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-        stream.read_exact(&mut buffer[4..4+length as usize]).expect("Failed to read from stream");
         let message_type = buffer[4];
         if message_type != 20 {
-            println!("Message didnt match");
+            eprintln!("Message didnt match");
             return None
         }
-        let bencoded_dict = &buffer[6..4+length as usize];
-        let outer_dict = decode_bencoded_value(bencoded_dict).0.get_map().unwrap();
-        let inner_dict = outer_dict.get("m").unwrap().get_map().unwrap();
-        let metadata_ext_id = inner_dict.get("ut_metadata")?;
-        let metadata_ext_id  = metadata_ext_id.get_int().unwrap() as u8;
+        if length < 2 || length as usize > Limits::network().max_alloc {
+            eprintln!("Invalid extension handshake length: {}", length);
+            return None;
+        }
+        // decode the handshake dictionary straight off the socket, then skip anything after it
+        let mut decoder = StreamDecoder::with_limits(BufReader::new((&mut stream).take(length as u64 - 2)), Limits::network());
+        let extension_handshake: ExtensionHandshake = from_value(decoder.read_value().ok()??).ok()?;
+        io::copy(&mut decoder.into_inner(), &mut io::sink()).ok()?;
+        let metadata_ext_id = extension_handshake.ut_metadata()?;
 
-        let mut request_dict = Map::new();
-        request_dict.insert("msg_type".as_bytes().to_vec(), Value::Int(0));
-        request_dict.insert("piece".as_bytes().to_vec(), Value::Int(0));
-        let request_dict_encoded = encode_value(Value::Map(request_dict));
+        let request_dict_encoded = encode_value(bencode!({ "msg_type": 0, "piece": 0 }));
 
         let mut info_request = vec![];
         info_request.extend((2 + request_dict_encoded.len() as u32).to_be_bytes());
@@ -151,80 +631,234 @@ impl Torrent {
         info_request.push(metadata_ext_id);
         info_request.extend(request_dict_encoded);
 
-        stream.write_all(&info_request).unwrap();
+        stream.write_all(&info_request).ok()?;
 
-        stream.read_exact(&mut buffer[..4]).unwrap();
+        stream.read_exact(&mut buffer[..4]).ok()?;
 
-        let length = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-        stream.read_exact(&mut buffer[4..4+length as usize]).unwrap();
-        let message_id = buffer[4];
-        let extension_message_id = buffer[5];
-        if message_id != 20 || extension_message_id != my_metadata_ext_id as u8 {
-            println!("Message types didnt match");
-            println!("Message_id: {}", message_id);
-            println!("Extension_message_id: {}", extension_message_id);
-            println!("Metadata_ext_id: {}", metadata_ext_id);
+        let length = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize;
+        if length < 2 || length > Limits::network().max_alloc {
+            eprintln!("Invalid metadata message length: {}", length);
             return None;
         }
-        let (_, rest) = decode_bencoded_value(&buffer[6..4+length as usize]);
-        let (metadata, _) = decode_bencoded_value(&rest);
-        let info = Info::new(metadata)?;
-        let info_hash = info.get_info_hash_bytes();
-        if info_hash != magnet.get_info_hash_bytes() {
-            println!("Info hash doesn't match");
+        let mut message = vec![0; length];
+        stream.read_exact(&mut message).ok()?;
+        let message_id = message[0];
+        let extension_message_id = message[1];
+        if message_id != 20 || extension_message_id != my_metadata_ext_id {
+            eprintln!("Message types didnt match");
+            eprintln!("Message_id: {}", message_id);
+            eprintln!("Extension_message_id: {}", extension_message_id);
+            eprintln!("Metadata_ext_id: {}", metadata_ext_id);
+            return None;
+        }
+        let (_, rest) = decode_with_limits(&message[2..], Limits::network()).ok()?;
+        let (metadata, after_metadata) = decode_with_limits(rest, Limits::network()).ok()?;
+        let info = Info::new(metadata, &rest[..rest.len() - after_metadata.len()]).ok()?;
+        let v1_matches = magnet.info_hash().is_none_or(|hash| info.hash == Some(hex::encode(hash)));
+        let v2_matches = magnet.info_hash_v2().is_none_or(|hash| info.hash_v2 == Some(hex::encode(hash)));
+        if !v1_matches || !v2_matches {
+            eprintln!("Info hash doesn't match");
+            return None
+        }
+        if magnet.exact_length().is_some_and(|length| length != info.length) {
+            eprintln!("Length doesn't match the magnet link");
             return None
         }
-        Some(Self { announce: magnet.get_url()?, info })
+        Some(info)
+    }
+    /// Web seed URLs (BEP 19).
+    pub fn url_list(&self) -> &[String] {
+        &self.url_list
+    }
+    /// HTTP seed URLs (BEP 17).
+    pub fn httpseeds(&self) -> &[String] {
+        &self.httpseeds
+    }
+    /// Turns the torrent back into metainfo. Keys this crate doesn't know are carried over unchanged.
+    pub fn to_value(&self) -> Value {
+        let mut map = self.known_fields();
+        for (key, value) in &self.extra {
+            map.insert(key.clone(), value.clone());
+        }
+        map.insert("info", self.info.to_value());
+        Value::Map(map)
+    }
+    // The typed fields as a dictionary, without `info`.
+    fn known_fields(&self) -> Map {
+        let fields = KnownFields {
+            announce: self.announce.as_deref(),
+            announce_list: &self.announce_list,
+            comment: self.comment.as_deref(),
+            created_by: self.created_by.as_deref(),
+            creation_date: self.creation_date,
+            encoding: self.encoding.as_deref(),
+            url_list: &self.url_list,
+            httpseeds: &self.httpseeds,
+            nodes: &self.nodes,
+            piece_layers: self.piece_layers.iter().map(|(root, layer)| (serde_bytes::Bytes::new(root), serde_bytes::Bytes::new(layer))).collect(),
+        };
+        match to_value(&fields) {
+            Ok(Value::Map(map)) => map,
+            other => unreachable!("a struct of strings and integers serializes to a dictionary, got {:?}", other),
+        }
+    }
+    /// Encodes the torrent as a `.torrent` file. The info dictionary is copied byte for byte, so the
+    /// info hash stays the same even if the original wasn't canonically encoded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let Value::Map(map) = self.to_value() else {
+            unreachable!("to_value always returns a dictionary")
+        };
+        encode_with_raw_info(&map, self.info.to_bytes())
     }
     pub fn print_info(&self) {
-        println!("Tracker URL: {}", self.announce);
+        if let Some(announce) = &self.announce {
+            println!("Tracker URL: {}", announce);
+        }
+        if !self.announce_list.is_empty() {
+            println!("Tracker Tiers:");
+            for (i, tier) in self.announce_list.iter().enumerate() {
+                println!("  Tier {}: {}", i + 1, tier.join(", "));
+            }
+        }
+        println!("Name: {}", self.info.name);
         println!("Length: {}", self.info.length);
-        println!("Info Hash: {}", self.info.hash);
+        let versions = match (self.info.supports_v1(), self.info.supports_v2()) {
+            (true, true) => "v1, v2 (hybrid)",
+            (false, true) => "v2",
+            _ => "v1",
+        };
+        println!("Protocol Versions: {}", versions);
+        if let Some(hash) = &self.info.hash {
+            println!("Info Hash: {}", hash);
+        }
+        if let Some(hash_v2) = &self.info.hash_v2 {
+            println!("Info Hash v2: {}", hash_v2);
+        }
         println!("Piece Length: {}", self.info.piece_length);
+        if self.info.private {
+            println!("Private: yes");
+        }
+        if let Some(source) = &self.info.source {
+            println!("Source: {}", source);
+        }
+        if let Some(comment) = &self.comment {
+            println!("Comment: {}", comment);
+        }
+        if let Some(created_by) = &self.created_by {
+            println!("Created By: {}", created_by);
+        }
+        if let Some(creation_date) = self.creation_date {
+            println!("Creation Date: {}", format_unix_time(creation_date));
+        }
+        if let Some(encoding) = &self.encoding {
+            println!("Encoding: {}", encoding);
+        }
+        for url in &self.url_list {
+            println!("Web Seed: {}", url);
+        }
+        for url in &self.httpseeds {
+            println!("HTTP Seed: {}", url);
+        }
+        for (host, port) in &self.nodes {
+            println!("DHT Node: {}:{}", host, port);
+        }
         println!("Piece Hashes: ");
         self.info.print_piece_hashes();
+        if self.info.multi_file {
+            println!("Files:");
+            self.info.print_file_tree();
+        }
     }
-    pub fn get_url(&self) -> String {
-        self.announce.clone()
-    }
-}
-
-pub struct Magnet {
-    tracker: Option<String>,
-    info_hash: String,
-    _filename: Option<String>,
-}
-
-impl Magnet {
-    pub fn new(magnet_link: &str) -> Option<Self> {
-        let params_str = magnet_link.strip_prefix("magnet:?").unwrap();
-        let mut filename = None;
-        let mut tracker = None;
-        let mut info_hash = None;
-        for param_str in params_str.split('&') {
-            let eq_index = param_str.find('=').unwrap();
-            let (name, value) = param_str.split_at(eq_index);
-            let value = value.strip_prefix('=').unwrap();
-            if name == "xt" {
-                info_hash = Some(value.strip_prefix("urn:btih:").unwrap().into());
-            } else if name == "dn" {
-                filename = Some(value.into());
-            } else if name == "tr" {
-                let tracker_decoded: String = urlencoding::decode(value).unwrap().into();
-                tracker = Some(tracker_decoded.trim().into());
-            }
+    /// What `print_info` shows, for `--json`. Unlike the text output, every file is listed, padding included.
+    pub fn info_json(&self) -> Json {
+        let mut versions = vec![];
+        if self.info.supports_v1() {
+            versions.push("v1");
         }
-        Some(Self { tracker, info_hash: info_hash?, _filename: filename})
+        if self.info.supports_v2() {
+            versions.push("v2");
+        }
+        let files: Vec<Json> = self.info.files.iter().map(|file| json!({
+            "path": file.path,
+            "length": file.length,
+            "offset": file.offset,
+            "padding": file.attributes.padding,
+            "executable": file.attributes.executable,
+            "hidden": file.attributes.hidden,
+            "symlink_path": file.symlink_path,
+        })).collect();
+        json!({
+            "announce": self.announce,
+            "announce_list": self.announce_list,
+            "name": self.info.name,
+            "length": self.info.length,
+            "protocol_versions": versions,
+            "info_hash": self.info.hash,
+            "info_hash_v2": self.info.hash_v2,
+            "piece_length": self.info.piece_length,
+            "private": self.info.private,
+            "source": self.info.source,
+            "comment": self.comment,
+            "created_by": self.created_by,
+            "creation_date": self.creation_date,
+            "encoding": self.encoding,
+            "url_list": self.url_list,
+            "httpseeds": self.httpseeds,
+            "nodes": self.nodes.iter().map(|(host, port)| json!({ "host": host, "port": port })).collect::<Vec<_>>(),
+            "piece_hashes": self.info.pieces.iter().map(hex::encode).collect::<Vec<_>>(),
+            "multi_file": self.info.multi_file,
+            "files": files,
+        })
     }
-    pub fn print_info(&self) {
-        println!("Tracker URL: {}", self.tracker.clone().unwrap());
-        println!("Info Hash: {}", self.info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::bencode::decode_bencoded_ref;
+
+    // Canonical at the top level, but with a custom key, leniently read fields and an info dictionary
+    // whose keys are out of order.
+    fn sloppy_torrent() -> Vec<u8> {
+        let mut encoded = b"d8:announce8:http://a13:announce-listll8:http://aelee7:comment2:\xff\xfe13:creation date9:yesterday".to_vec();
+        encoded.extend(b"4:infod4:name1:f12:piece lengthi16e6:lengthi3e6:pieces20:");
+        encoded.extend(Sha1::digest(b"abc"));
+        encoded.extend(b"e8:url-list8:http://w8:x-customli1ei2eee");
+        encoded
     }
-    pub fn get_info_hash_bytes(&self) -> Vec<u8> {
-        let hex_str = &self.info_hash;
-        decode(hex_str).unwrap()
+
+    fn parse(encoded: &[u8]) -> Torrent {
+        let (value, _) = decode_bencoded_ref(encoded).unwrap();
+        Torrent::new(&value).unwrap()
     }
-    pub fn get_url(&self) -> Option<String> {
-        self.tracker.clone()
+
+    #[test]
+    fn writes_back_the_bytes_it_was_read_from() {
+        let encoded = sloppy_torrent();
+        let torrent = parse(&encoded);
+        assert_eq!(torrent.creation_date, None);
+        assert_eq!(torrent.url_list, ["http://w"]);
+        assert_eq!(torrent.announce_list, [["http://a"]]);
+        assert!(!torrent.info.is_canonical());
+        assert_eq!(torrent.to_bytes(), encoded);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn keeps_the_info_bytes_when_the_metadata_changes() {
+        let encoded = sloppy_torrent();
+        let torrent = parse(&encoded);
+        let info_hash = torrent.info.get_info_hash();
+        let Value::Map(mut map) = torrent.to_value() else {
+            unreachable!("to_value always returns a dictionary")
+        };
+        map.insert("comment", "fixed");
+        map.insert("info", bencode!({ "name": "ignored" }));
+        let edited = torrent.with_metadata(map).unwrap();
+        assert_eq!(edited.comment.as_deref(), Some("fixed"));
+        let old_comment = b"7:comment2:\xff\xfe";
+        let at = encoded.windows(old_comment.len()).position(|window| window == old_comment).unwrap();
+        let expected = [&encoded[..at], b"7:comment5:fixed", &encoded[at + old_comment.len()..]].concat();
+        assert_eq!(edited.to_bytes(), expected);
+        assert_eq!(parse(&edited.to_bytes()).info.get_info_hash(), info_hash);
+    }
+}
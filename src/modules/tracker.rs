@@ -0,0 +1,104 @@
+use std::io::BufReader;
+
+use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+use rand::{rng, seq::SliceRandom};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{bytes_to_peer_list, modules::{bencode::Limits, stream::{StreamDecoder, StreamError}, value_serde::{self, from_value}}};
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid response: {0}")]
+    Decode(#[from] StreamError),
+    #[error("invalid response: {0}")]
+    Response(#[from] value_serde::Error),
+    #[error("empty response")]
+    Empty,
+    #[error("tracker error: {0}")]
+    Failure(String),
+    #[error("no peers")]
+    NoPeers,
+    #[error("no tracker responded{}", format_failures(.0))]
+    Exhausted(Vec<(String, TrackerError)>),
+}
+
+fn format_failures(failures: &[(String, TrackerError)]) -> String {
+    failures.iter().map(|(url, err)| format!("\n  {}: {}", url, err)).collect()
+}
+
+#[derive(Deserialize)]
+struct TrackerResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default, with = "serde_bytes")]
+    peers: Vec<u8>,
+}
+
+/// Asks the tracker at `announce` for peers, returning them as (ip, port) pairs.
+pub fn announce(announce: &str, info_hash: &[u8], peer_id: &str, left: usize) -> Result<Vec<(String, u16)>, TrackerError> {
+    let info_hash_encoded = percent_encode(info_hash, NON_ALPHANUMERIC).to_string();
+    let port = 6881;
+    let separator = if announce.contains('?') { '&' } else { '?' };
+    let query_params = format!("{}info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&compact=1", separator, info_hash_encoded, peer_id, port, left);
+    // the URL is already known to the caller and the query string only adds noise to the message
+    let response = reqwest::blocking::get(String::from(announce) + &query_params)
+        .and_then(|response| response.error_for_status())
+        .map_err(reqwest::Error::without_url)?;
+    let mut decoder = StreamDecoder::with_limits(BufReader::new(response), Limits::network());
+    let response: TrackerResponse = from_value(decoder.read_value()?.ok_or(TrackerError::Empty)?)?;
+    if let Some(reason) = response.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    Ok(bytes_to_peer_list(&response.peers))
+}
+
+/// Trackers grouped into tiers as described by BEP 12.
+#[derive(Debug, Clone)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerList {
+    /// Uses `announce-list` when present and falls back to the single `announce` URL otherwise.
+    /// Each tier is shuffled once, up front.
+    pub fn new(announce: Option<&str>, announce_list: &[Vec<String>]) -> Self {
+        let mut tiers: Vec<Vec<String>> = announce_list.iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if tiers.is_empty() {
+            tiers.extend(announce.map(|url| vec![url.to_string()]));
+        }
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng());
+        }
+        Self { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Tries trackers tier by tier until one returns peers, a tracker without any counts as failed.
+    /// The tracker that answered moves to the front of its tier so it's asked first next time.
+    pub fn get_peers(&mut self, info_hash: &[u8], peer_id: &str, left: usize) -> Result<Vec<(String, u16)>, TrackerError> {
+        let mut failures = vec![];
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                match announce(&tier[i], info_hash, peer_id, left) {
+                    Ok(peers) if !peers.is_empty() => {
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        return Ok(peers);
+                    },
+                    Ok(_) => failures.push((tier[i].clone(), TrackerError::NoPeers)),
+                    Err(err) => failures.push((tier[i].clone(), err)),
+                }
+            }
+        }
+        Err(TrackerError::Exhausted(failures))
+    }
+}
@@ -0,0 +1,122 @@
+use serde::de;
+use sha2::{Digest, Sha256};
+
+use crate::modules::{value::{Map, Value}, value_serde::Error};
+
+/// Size of the blocks the per-file merkle trees are built over (BEP 52).
+pub const BLOCK_SIZE: u64 = 16 * 1024;
+
+/// A file from a v2 `file tree`.
+#[derive(Debug, Clone)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Root of the file's merkle tree; empty files don't have one.
+    pub pieces_root: Option<[u8; 32]>,
+    /// BEP 47 attributes, see `FileAttributes`.
+    pub attr: Option<String>,
+    pub symlink_path: Option<Vec<String>>,
+}
+
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+/// SHA-256 of the info dictionary's encoded bytes, as lowercase hex.
+pub fn info_hash_v2_hex(raw_info: &[u8]) -> String {
+    hex::encode(sha256(raw_info))
+}
+
+fn error_at(path: &[String], message: impl std::fmt::Display) -> Error {
+    let mut err = <Error as de::Error>::custom(message);
+    for segment in path.iter().rev() {
+        err = err.prefixed(segment.clone());
+    }
+    err.prefixed("file tree")
+}
+
+/// Flattens a `file tree` into its files, in the tree's (sorted) order. A file is a dictionary whose
+/// only key is the empty string, mapping to its `length` and `pieces root`.
+pub fn parse_file_tree(tree: &Map) -> Result<Vec<V2File>, Error> {
+    let mut files = vec![];
+    walk_file_tree(tree, &mut vec![], &mut files)?;
+    if files.is_empty() {
+        return Err(error_at(&[], "a torrent needs at least one file"));
+    }
+    Ok(files)
+}
+
+fn walk_file_tree(tree: &Map, path: &mut Vec<String>, files: &mut Vec<V2File>) -> Result<(), Error> {
+    for (key, node) in tree {
+        let Ok(component) = String::from_utf8(key.clone()) else {
+            return Err(error_at(path, "path components must be UTF-8"));
+        };
+        if component.is_empty() {
+            return Err(error_at(path, "unexpected file entry inside a directory"));
+        }
+        path.push(component);
+        let Some(node) = node.as_map() else {
+            return Err(error_at(path, "expected a dictionary"));
+        };
+        match node.get("") {
+            Some(_) if node.len() > 1 => return Err(error_at(path, "a file entry can't also be a directory")),
+            Some(Value::Map(file)) => files.push(parse_file(file, path)?),
+            Some(_) => return Err(error_at(path, "file entry must be a dictionary")),
+            None => walk_file_tree(node, path, files)?,
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn parse_file(file: &Map, path: &[String]) -> Result<V2File, Error> {
+    let length = file.get("length")
+        .and_then(Value::as_int)
+        .and_then(|length| u64::try_from(length).ok())
+        .ok_or_else(|| error_at(path, "missing or invalid `length`"))?;
+    let pieces_root = match file.get("pieces root") {
+        Some(root) => Some(root.as_bytes()
+            .and_then(|root| root.try_into().ok())
+            .ok_or_else(|| error_at(path, "`pieces root` must be 32 bytes"))?),
+        None if length > 0 => return Err(error_at(path, "missing field `pieces root`")),
+        None => None,
+    };
+    let attr = match file.get("attr") {
+        Some(attr) => Some(attr.as_str().ok_or_else(|| error_at(path, "`attr` must be a string"))?.to_string()),
+        None => None,
+    };
+    let symlink_path = match file.get("symlink path") {
+        Some(target) => Some(target.as_list()
+            .and_then(|components| components.iter().map(|c| c.as_str().map(String::from)).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| error_at(path, "`symlink path` must be a list of strings"))?),
+        None => None,
+    };
+    Ok(V2File { path: path.to_vec(), length, pieces_root, attr, symlink_path })
+}
+
+/// Root of a merkle tree whose leaves are padded up to a power of two with `padding`.
+pub fn merkle_root(mut layer: Vec<[u8; 32]>, padding: [u8; 32]) -> [u8; 32] {
+    layer.resize(layer.len().next_power_of_two(), padding);
+    while layer.len() > 1 {
+        layer = layer.chunks(2).map(|pair| sha256(&[pair[0], pair[1]].concat())).collect();
+    }
+    layer[0]
+}
+
+/// Checks a `piece layers` entry against the file's `pieces root`. Past the end of the file the layer is
+/// padded with the root of a piece made of all-zero leaf hashes.
+pub fn verify_piece_layer(file: &V2File, piece_length: u64, layer: &[u8]) -> Result<(), String> {
+    let pieces = file.length.div_ceil(piece_length);
+    if layer.len() as u64 != pieces * 32 {
+        return Err(format!("expected {} hashes, found {} bytes", pieces, layer.len()));
+    }
+    let mut piece_padding = [0u8; 32];
+    for _ in 0..(piece_length / BLOCK_SIZE).trailing_zeros() {
+        piece_padding = sha256(&[piece_padding, piece_padding].concat());
+    }
+    let hashes = layer.chunks(32).map(|hash| hash.try_into().expect("chunks are 32 bytes")).collect();
+    if Some(merkle_root(hashes, piece_padding)) != file.pieces_root {
+        return Err("hashes don't add up to the file's pieces root".into());
+    }
+    Ok(())
+}
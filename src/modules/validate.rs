@@ -0,0 +1,406 @@
+use std::{collections::HashSet, fmt};
+
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::modules::{bencode::{decode_bencoded_ref, decode_strict, DecodeError, StrictError}, v2::{parse_file_tree, verify_piece_layer, BLOCK_SIZE}, value::{format_path, Map, PathSegment, Value}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// Clients will likely handle it, but it's unusual or wasteful.
+    Warning,
+    /// Clients will reject the torrent or misbehave with it.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// One problem found in a torrent. `code` is a stable identifier for tools, `path` points at the
+/// offending value in the same notation `query` takes.
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() { "<root>" } else { &self.path };
+        write!(f, "{}: {}: {} [{}]", self.severity, path, self.message, self.code)
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    fn error(&mut self, code: &'static str, path: impl Into<String>, message: impl Into<String>) {
+        self.findings.push(Finding { severity: Severity::Error, code, path: path.into(), message: message.into() });
+    }
+
+    fn warning(&mut self, code: &'static str, path: impl Into<String>, message: impl Into<String>) {
+        self.findings.push(Finding { severity: Severity::Warning, code, path: path.into(), message: message.into() });
+    }
+}
+
+/// Checks the metainfo in `encoded` for consistency. Unlike `Torrent::new`, which stops at the first
+/// problem, this keeps going and reports everything it finds, errors first. Only input that isn't
+/// bencode at all fails outright.
+pub fn validate_torrent(encoded: &[u8]) -> Result<Vec<Finding>, DecodeError> {
+    let mut report = Report::default();
+    if let Err(StrictError::NonCanonical(violations)) = decode_strict(encoded) {
+        for violation in violations {
+            report.warning("non-canonical", violation.path, format!("{} (byte {})", violation.kind, violation.offset));
+        }
+    }
+    let (torrent, _) = decode_bencoded_ref(encoded)?;
+    match torrent.to_owned_value() {
+        Value::Map(torrent) => check_torrent(&torrent, &mut report),
+        _ => report.error("not-a-dictionary", "", "a torrent must be a dictionary"),
+    }
+    report.findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    Ok(report.findings)
+}
+
+fn check_torrent(torrent: &Map, report: &mut Report) {
+    check_trackers(torrent, report);
+    let info = match torrent.get("info") {
+        Some(Value::Map(info)) => info,
+        Some(_) => return report.error("invalid-type", "info", "must be a dictionary"),
+        None => return report.error("missing-field", "info", "missing the info dictionary"),
+    };
+
+    match info.get("name") {
+        Some(name) => {
+            check_path_component(name, "info.name", report);
+        },
+        None => report.error("missing-field", "info.name", "missing the name"),
+    }
+
+    let v2 = match info.get("meta version") {
+        None => false,
+        Some(Value::Int(2)) => true,
+        Some(_) => {
+            report.error("unsupported-version", "info.meta version", "only version 2 is defined");
+            false
+        },
+    };
+    let piece_length = check_piece_length(info, v2, report);
+    let total_length = check_files(info, v2, report);
+    let v2_total_length = if v2 { check_file_tree(torrent, info, piece_length, report) } else { None };
+
+    if let (Some(v1), Some(v2)) = (total_length, v2_total_length) {
+        if v1 != v2 {
+            report.error("length-mismatch", "info", format!("the v1 files add up to {} bytes but the file tree to {}", v1, v2));
+        }
+    }
+    match info.get("pieces") {
+        Some(Value::String(pieces)) => {
+            if pieces.len() % 20 != 0 {
+                report.error("truncated-pieces", "info.pieces", format!("{} bytes isn't a whole number of 20-byte hashes", pieces.len()));
+            }
+            if let (Some(piece_length), Some(total_length)) = (piece_length, total_length) {
+                let expected = total_length.div_ceil(piece_length);
+                let found = pieces.len() as u64 / 20;
+                if found != expected {
+                    report.error("piece-count", "info.pieces", format!(
+                        "{} bytes in pieces of {} need {} hashes, found {}", total_length, piece_length, expected, found));
+                }
+            }
+        },
+        Some(_) => report.error("invalid-type", "info.pieces", "must be a byte string"),
+        None if !v2 => report.error("missing-field", "info.pieces", "missing the piece hashes"),
+        None => {},
+    }
+    if total_length == Some(0) || v2_total_length == Some(0) {
+        report.warning("empty-torrent", "info", "the torrent has no content");
+    }
+
+    match info.get("private") {
+        None | Some(Value::Int(0 | 1)) => {},
+        Some(_) => report.warning("invalid-private", "info.private", "should be 0 or 1"),
+    }
+}
+
+fn check_piece_length(info: &Map, v2: bool, report: &mut Report) -> Option<u64> {
+    let path = "info.piece length";
+    let piece_length = match info.get("piece length").map(Value::as_int) {
+        Some(Some(length)) if length > 0 => length as u64,
+        Some(Some(length)) => {
+            report.error("invalid-piece-length", path, format!("{} is not positive", length));
+            return None;
+        },
+        Some(None) => {
+            report.error("invalid-type", path, "must be an integer");
+            return None;
+        },
+        None => {
+            report.error("missing-field", path, "missing the piece length");
+            return None;
+        },
+    };
+    if v2 && (piece_length < BLOCK_SIZE || !piece_length.is_power_of_two()) {
+        report.error("invalid-piece-length", path, format!("{} must be a power of two of at least 16 KiB in a v2 torrent", piece_length));
+    } else if !piece_length.is_power_of_two() {
+        report.warning("piece-length-not-power-of-two", path, format!("{} is not a power of two", piece_length));
+    }
+    Some(piece_length)
+}
+
+// Checks the v1 `length` or `files` and returns the total content length when it's well formed.
+fn check_files(info: &Map, v2: bool, report: &mut Report) -> Option<u64> {
+    match (info.get("length"), info.get("files")) {
+        (Some(_), Some(_)) => {
+            report.error("length-and-files", "info", "only one of length and files may be present");
+            None
+        },
+        (Some(length), None) => check_length(length, "info.length", report),
+        (None, Some(Value::List(files))) => {
+            if files.is_empty() {
+                report.error("no-files", "info.files", "a multi-file torrent needs at least one file");
+            }
+            let mut total = Some(0u64);
+            let mut seen = HashSet::new();
+            for (i, file) in files.iter().enumerate() {
+                let path = format!("info.files[{}]", i);
+                let Value::Map(file) = file else {
+                    report.error("invalid-type", path, "must be a dictionary");
+                    total = None;
+                    continue;
+                };
+                let length = match file.get("length") {
+                    Some(length) => check_length(length, &format!("{}.length", path), report),
+                    None => {
+                        report.error("missing-field", format!("{}.length", path), "missing the file length");
+                        None
+                    },
+                };
+                total = total.zip(length).and_then(|(total, length)| total.checked_add(length));
+                if let Some(components) = check_file_path(file.get("path"), &format!("{}.path", path), report) {
+                    let joined = join_path(&components);
+                    if !seen.insert(components) {
+                        report.error("duplicate-path", format!("{}.path", path), format!("{} appears more than once", joined));
+                    }
+                }
+                check_symlink(file, &path, report);
+            }
+            check_path_prefixes(&seen, report);
+            total
+        },
+        (None, Some(_)) => {
+            report.error("invalid-type", "info.files", "must be a list");
+            None
+        },
+        (None, None) if v2 => None,
+        (None, None) => {
+            report.error("missing-field", "info", "missing length or files");
+            None
+        },
+    }
+}
+
+fn check_length(length: &Value, path: &str, report: &mut Report) -> Option<u64> {
+    match length {
+        Value::Int(length) if *length < 0 => {
+            report.error("negative-length", path, format!("{} is negative", length));
+            None
+        },
+        Value::Int(length) => Some(*length as u64),
+        Value::BigInt(length) if length.is_negative() => {
+            report.error("negative-length", path, format!("{} is negative", length));
+            None
+        },
+        Value::BigInt(length) => {
+            report.error("length-overflow", path, format!("{} is too large", length));
+            None
+        },
+        _ => {
+            report.error("invalid-type", path, "must be an integer");
+            None
+        },
+    }
+}
+
+fn check_file_path(path: Option<&Value>, location: &str, report: &mut Report) -> Option<Vec<Vec<u8>>> {
+    let Some(path) = path else {
+        report.error("missing-field", location, "missing the file path");
+        return None;
+    };
+    let Some(components) = path.as_list() else {
+        report.error("invalid-type", location, "must be a list of strings");
+        return None;
+    };
+    if components.is_empty() {
+        report.error("empty-path", location, "a file path needs at least one component");
+        return None;
+    }
+    let mut valid = true;
+    for (i, component) in components.iter().enumerate() {
+        valid &= check_path_component(component, &format!("{}[{}]", location, i), report);
+    }
+    valid.then(|| components.iter().filter_map(|component| component.as_bytes().map(<[u8]>::to_vec)).collect())
+}
+
+// A path component is joined onto the download directory as is, so it must not be able to leave it.
+fn check_path_component(component: &Value, location: &str, report: &mut Report) -> bool {
+    let Some(bytes) = component.as_bytes() else {
+        report.error("invalid-type", location, "must be a string");
+        return false;
+    };
+    let text = String::from_utf8_lossy(bytes);
+    let problem = if bytes.is_empty() {
+        Some(("empty-name", "is empty".to_string()))
+    } else if bytes == b"." || bytes == b".." {
+        Some(("path-traversal", format!("{:?} would point outside the torrent", text)))
+    } else if bytes.contains(&b'/') || bytes.contains(&b'\\') {
+        Some(("path-separator", format!("{:?} contains a path separator", text)))
+    } else if bytes.contains(&0) {
+        Some(("invalid-name", format!("{:?} contains a NUL byte", text)))
+    } else if bytes.len() >= 2 && bytes[1] == b':' && bytes[0].is_ascii_alphabetic() {
+        Some(("absolute-path", format!("{:?} looks like a drive letter", text)))
+    } else {
+        None
+    };
+    match problem {
+        Some((code, message)) => {
+            report.error(code, location, message);
+            false
+        },
+        None => {
+            if std::str::from_utf8(bytes).is_err() {
+                report.warning("non-utf8-name", location, "is not valid UTF-8");
+            }
+            true
+        },
+    }
+}
+
+// BEP 47: a file with the `l` attribute points at its `symlink path`, relative to the torrent's root.
+fn check_symlink(file: &Map, location: &str, report: &mut Report) {
+    let is_symlink = file.get("attr").and_then(Value::as_str).is_some_and(|attr| attr.contains('l'));
+    match file.get("symlink path") {
+        Some(_) if !is_symlink => {
+            report.warning("unused-symlink-path", format!("{}.symlink path", location), "set on a file without the `l` attribute");
+        },
+        Some(target) => {
+            check_file_path(Some(target), &format!("{}.symlink path", location), report);
+        },
+        None if is_symlink => report.error("missing-field", format!("{}.symlink path", location), "symlink without a target"),
+        None => {},
+    }
+    if is_symlink && file.get("length").and_then(Value::as_int).is_some_and(|length| length > 0) {
+        report.error("symlink-length", format!("{}.length", location), "a symlink can't have content");
+    }
+}
+
+fn join_path(components: &[Vec<u8>]) -> String {
+    components.iter().map(|component| String::from_utf8_lossy(component)).collect::<Vec<_>>().join("/")
+}
+
+// A file can't share its path with a directory holding other files.
+fn check_path_prefixes(paths: &HashSet<Vec<Vec<u8>>>, report: &mut Report) {
+    let mut conflicts: Vec<String> = paths.iter()
+        .filter(|path| (1..path.len()).any(|len| paths.contains(&path[..len].to_vec())))
+        .map(|path| join_path(path))
+        .collect();
+    conflicts.sort();
+    for conflict in conflicts {
+        report.error("file-directory-conflict", "info.files", format!("{} is inside a path that is also a file", conflict));
+    }
+}
+
+// Checks the v2 file tree and its piece layers, returning the total length of its files.
+fn check_file_tree(torrent: &Map, info: &Map, piece_length: Option<u64>, report: &mut Report) -> Option<u64> {
+    let tree = match info.get("file tree") {
+        Some(Value::Map(tree)) => tree,
+        Some(_) => {
+            report.error("invalid-type", "info.file tree", "must be a dictionary");
+            return None;
+        },
+        None => {
+            report.error("missing-field", "info.file tree", "missing the file tree");
+            return None;
+        },
+    };
+    let files = match parse_file_tree(tree) {
+        Ok(files) => files,
+        Err(err) => {
+            report.error("invalid-file-tree", "info.file tree", err.to_string());
+            return None;
+        },
+    };
+    for file in &files {
+        let mut location = vec![PathSegment::Key(b"info".to_vec()), PathSegment::Key(b"file tree".to_vec())];
+        for component in &file.path {
+            location.push(PathSegment::Key(component.clone().into_bytes()));
+            check_path_component(&Value::from(component.as_str()), &format_path(&location), report);
+        }
+    }
+    let layers = torrent.get("piece layers").and_then(Value::as_map);
+    if let Some(piece_length) = piece_length.filter(|length| length.is_power_of_two() && *length >= BLOCK_SIZE) {
+        for file in files.iter().filter(|file| file.length > piece_length) {
+            let root = file.pieces_root.expect("non-empty files have a pieces root");
+            let name = file.path.join("/");
+            match layers.and_then(|layers| layers.get_bytes(&root)).and_then(Value::as_bytes) {
+                Some(layer) => if let Err(message) = verify_piece_layer(file, piece_length, layer) {
+                    report.error("invalid-piece-layer", "piece layers", format!("layer for {}: {}", name, message));
+                },
+                None => report.error("missing-piece-layer", "piece layers", format!("missing the layer for {}", name)),
+            }
+        }
+    }
+    files.iter().try_fold(0u64, |total, file| total.checked_add(file.length))
+}
+
+fn check_trackers(torrent: &Map, report: &mut Report) {
+    match torrent.get("announce") {
+        Some(url) => check_tracker_url(url, "announce", report),
+        None if !torrent.contains_key("announce-list") => {
+            report.warning("no-trackers", "", "no trackers, peers can only be found through DHT or magnet links");
+        },
+        None => {},
+    }
+    let Some(tiers) = torrent.get("announce-list") else {
+        return;
+    };
+    let Some(tiers) = tiers.as_list() else {
+        return report.error("invalid-type", "announce-list", "must be a list of tiers");
+    };
+    for (i, tier) in tiers.iter().enumerate() {
+        let path = format!("announce-list[{}]", i);
+        match tier.as_list() {
+            Some([]) => report.warning("empty-tier", path, "tier has no trackers"),
+            Some(urls) => for (j, url) in urls.iter().enumerate() {
+                check_tracker_url(url, &format!("{}[{}]", path, j), report);
+            },
+            None => report.error("invalid-type", path, "must be a list of URLs"),
+        }
+    }
+}
+
+fn check_tracker_url(url: &Value, path: &str, report: &mut Report) {
+    let Some(text) = url.as_str() else {
+        return report.error("invalid-type", path, "must be a UTF-8 string");
+    };
+    match Url::parse(text) {
+        Ok(url) if matches!(url.scheme(), "http" | "https" | "udp" | "wss") => {
+            if url.host_str().is_none_or(str::is_empty) {
+                report.error("malformed-url", path, format!("{:?} has no host", text));
+            }
+        },
+        Ok(url) => report.warning("unsupported-scheme", path, format!("{:?} uses unknown scheme {}", text, url.scheme())),
+        Err(err) => report.error("malformed-url", path, format!("{:?}: {}", text, err)),
+    }
+}
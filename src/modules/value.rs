@@ -1,33 +1,43 @@
-use std::{collections::BTreeMap, fmt};
+use std::{collections::{btree_map, BTreeMap}, fmt, str::FromStr};
 
-#[derive(Debug, Clone)]
+use thiserror::Error;
+
+use crate::modules::json::{map_to_json, value_to_json};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Map(BTreeMap<Vec<u8>, Value>);
 
-#[allow(dead_code)]
 impl Map {
     pub fn new() -> Self {
         Self(BTreeMap::new())
     }
-    pub fn insert(&mut self, k: Vec<u8>, v: Value) {
-        self.0.insert(k, v);
+    pub fn insert(&mut self, k: impl Into<Vec<u8>>, v: impl Into<Value>) {
+        self.0.insert(k.into(), v.into());
     }
-    pub fn to_string(&self) -> String {
-        let mut map_string = String::from('{');
-        for (i, (k, v)) in self.0.iter().enumerate() {
-            let key_string = String::from_utf8(k.clone()).unwrap_or("String not in utf8".into());
-            map_string.push_str(&format!("\"{}\":{}", key_string, v));
-            if i != self.0.len() - 1 {
-                map_string.push(',');
-            }
-        }
-        map_string.push('}');
-        map_string
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.0.get(key.as_bytes())
     }
-    pub fn get(&self, key: &str) -> Option<Value> {
-        match self.0.get(key.as_bytes()) {
-            Some(value) => Some(value.clone()),
-            None => None
-        }
+    /// Like [`Map::get`], for keys that aren't valid UTF-8.
+    pub fn get_bytes(&self, key: &[u8]) -> Option<&Value> {
+        self.0.get(key)
+    }
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.0.get_mut(key.as_bytes())
+    }
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.0.remove(key.as_bytes())
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.contains_key(key.as_bytes())
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn iter(&self) -> btree_map::Iter<'_, Vec<u8>, Value> {
+        self.0.iter()
     }
     pub fn keys(&self) -> Vec<String> {
         let mut result = vec![];
@@ -40,84 +50,519 @@ impl Map {
 }
 
 
-impl Iterator for Map {
+impl Default for Map {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for Map {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", map_to_json(self))
+    }
+}
+
+impl IntoIterator for Map {
     type Item = (Vec<u8>, Value);
+    type IntoIter = btree_map::IntoIter<Vec<u8>, Value>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.pop_first()
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
     }
 }
 
-#[derive(Debug, Clone)]
+impl<'a> IntoIterator for &'a Map {
+    type Item = (&'a Vec<u8>, &'a Value);
+    type IntoIter = btree_map::Iter<'a, Vec<u8>, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl<K: Into<Vec<u8>>, V: Into<Value>> FromIterator<(K, V)> for Map {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (k, v) in iter {
+            map.insert(k, v);
+        }
+        map
+    }
+}
+
+/// An integer of any size, kept as its canonical decimal digits. The decoders only produce one when
+/// the number doesn't fit in an `i64`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInt(String);
+
+impl BigInt {
+    /// Parses the digits between `i` and `e`. Returns `None` unless they are an optional `-` followed by
+    /// at least one ASCII digit.
+    pub fn parse(digits: &[u8]) -> Option<Self> {
+        let (negative, magnitude) = match digits.strip_prefix(b"-") {
+            Some(magnitude) => (true, magnitude),
+            None => (false, digits),
+        };
+        if magnitude.is_empty() || !magnitude.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let first_nonzero = magnitude.iter().position(|b| *b != b'0').unwrap_or(magnitude.len() - 1);
+        let magnitude = std::str::from_utf8(&magnitude[first_nonzero..]).ok()?;
+        if negative && magnitude != "0" {
+            Some(Self(format!("-{}", magnitude)))
+        } else {
+            Some(Self(magnitude.into()))
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    pub fn is_negative(&self) -> bool {
+        self.0.starts_with('-')
+    }
+    pub fn to_i64(&self) -> Option<i64> {
+        self.0.parse().ok()
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = IntError;
+
+    fn from_str(s: &str) -> Result<Self, IntError> {
+        Self::parse(s.as_bytes()).ok_or_else(|| IntError::Invalid(s.into()))
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum IntError {
+    #[error("expected an integer, found {0}")]
+    NotAnInteger(&'static str),
+    #[error("integer {0} does not fit in 64 bits")]
+    Overflow(BigInt),
+    #[error("{0:?} is not a decimal integer")]
+    Invalid(String),
+}
+
+/// Decodes the digits of a bencoded integer, falling back to a [`BigInt`] when they overflow an `i64`.
+pub(crate) fn parse_int(digits: &[u8]) -> Option<Result<i64, BigInt>> {
+    let big = BigInt::parse(digits)?;
+    Some(big.to_i64().ok_or(big))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Value {
     String(Vec<u8>),
     Int(i64),
+    /// An integer outside the `i64` range.
+    BigInt(BigInt),
     List(Vec<Self>),
     Map(Map),
 }
 
 impl Value {
-    pub fn to_string(&self) -> String {
-        match self {
-            Self::String(value) => {
-                if let Ok(string_value) = String::from_utf8(value.clone()) {
-                    string_value
-                } else {
-                    "String not in utf8".into()
-                }
-            },
-            Self::Int(value) => value.to_string(),
-            Self::List(list) => {
-                let mut output = String::from("[");
-                for (i, val) in list.iter().enumerate() {
-                    output.push_str(&format!("{}", val));
-                    if i != list.len() - 1 {
-                        output.push(',');
-                    }
-                }
-                output.push(']');
-                output
-            },
-            Self::Map(map) => {
-                map.to_string()
-            },
-        }
-    }
-    pub fn get_map(self) -> Option<Map> {
+    pub fn as_map(&self) -> Option<&Map> {
         if let Self::Map(map) = self {
             Some(map)
         } else {
             None
         }
     }
-    pub fn get_string(self) -> Option<Vec<u8>> {
+    pub fn as_bytes(&self) -> Option<&[u8]> {
         if let Self::String(s) = self {
             Some(s)
         } else {
             None
         }
     }
-    pub fn get_int(self) -> Option<i64> {
+    /// Returns the string if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+    /// Returns the integer if it fits in an `i64`, see [`Value::to_i64`] to tell the failures apart.
+    pub fn as_int(&self) -> Option<i64> {
         if let Self::Int(i) = self {
+            Some(*i)
+        } else {
+            None
+        }
+    }
+    pub fn as_big_int(&self) -> Option<&BigInt> {
+        if let Self::BigInt(i) = self {
             Some(i)
         } else {
             None
         }
     }
-    pub fn _get_list(self) -> Option<Vec<Self>> {
+    pub fn to_i64(&self) -> Result<i64, IntError> {
+        match self {
+            Self::Int(i) => Ok(*i),
+            Self::BigInt(i) => Err(IntError::Overflow(i.clone())),
+            other => Err(IntError::NotAnInteger(other.kind())),
+        }
+    }
+    pub fn as_list(&self) -> Option<&[Self]> {
         if let Self::List(l) = self {
             Some(l)
         } else {
             None
         }
     }
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::String(_) => "a string",
+            Self::Int(_) | Self::BigInt(_) => "an integer",
+            Self::List(_) => "a list",
+            Self::Map(_) => "a dictionary",
+        }
+    }
+    /// Looks up a nested value by a path such as `info.files[3].path` or `announce-list[0]`,
+    /// see [`parse_path`] for the syntax.
+    pub fn pointer(&self, path: &str) -> Result<&Value, PathError> {
+        let segments = parse_path(path)?;
+        let mut current = self;
+        for (i, segment) in segments.iter().enumerate() {
+            let resolved = format_path(&segments[..i]);
+            current = match (current, segment) {
+                (Self::Map(map), PathSegment::Key(key)) => map.0.get(key)
+                    .ok_or_else(|| PathError::MissingKey { path: resolved, segment: segment.to_string() })?,
+                (Self::List(list), PathSegment::Index(index)) => list.get(*index)
+                    .ok_or(PathError::IndexOutOfRange { path: resolved, index: *index, len: list.len() })?,
+                (value, segment) => return Err(PathError::TypeMismatch { path: resolved, segment: segment.to_string(), found: value.kind() }),
+            };
+        }
+        Ok(current)
+    }
+    pub fn pointer_mut(&mut self, path: &str) -> Result<&mut Value, PathError> {
+        self.resolve_mut(&parse_path(path)?)
+    }
+    fn resolve_mut(&mut self, segments: &[PathSegment]) -> Result<&mut Value, PathError> {
+        let mut current = self;
+        for (i, segment) in segments.iter().enumerate() {
+            let resolved = format_path(&segments[..i]);
+            current = match (current, segment) {
+                (Self::Map(map), PathSegment::Key(key)) => map.0.get_mut(key)
+                    .ok_or_else(|| PathError::MissingKey { path: resolved, segment: segment.to_string() })?,
+                (Self::List(list), PathSegment::Index(index)) => {
+                    let len = list.len();
+                    list.get_mut(*index).ok_or(PathError::IndexOutOfRange { path: resolved, index: *index, len })?
+                },
+                (value, segment) => return Err(PathError::TypeMismatch { path: resolved, segment: segment.to_string(), found: value.kind() }),
+            };
+        }
+        Ok(current)
+    }
+    /// Stores `value` at `path`, adding the final key if the dictionary doesn't have it yet, or appending
+    /// when the final index is one past the end of a list. Returns the value that was replaced.
+    pub fn set_pointer(&mut self, path: &str, value: Value) -> Result<Option<Value>, PathError> {
+        let segments = parse_path(path)?;
+        let Some((last, parents)) = segments.split_last() else {
+            return Ok(Some(std::mem::replace(self, value)));
+        };
+        let resolved = format_path(parents);
+        match (self.resolve_mut(parents)?, last) {
+            (Self::Map(map), PathSegment::Key(key)) => Ok(map.0.insert(key.clone(), value)),
+            (Self::List(list), PathSegment::Index(index)) if *index == list.len() => {
+                list.push(value);
+                Ok(None)
+            },
+            (Self::List(list), PathSegment::Index(index)) => {
+                let len = list.len();
+                let slot = list.get_mut(*index).ok_or(PathError::IndexOutOfRange { path: resolved, index: *index, len })?;
+                Ok(Some(std::mem::replace(slot, value)))
+            },
+            (parent, segment) => Err(PathError::TypeMismatch { path: resolved, segment: segment.to_string(), found: parent.kind() }),
+        }
+    }
+    /// Removes and returns the value at `path`; later list elements shift down.
+    pub fn remove_pointer(&mut self, path: &str) -> Result<Value, PathError> {
+        let segments = parse_path(path)?;
+        let Some((last, parents)) = segments.split_last() else {
+            return Err(PathError::Syntax { position: 0, reason: "can't remove the whole document" });
+        };
+        let resolved = format_path(parents);
+        match (self.resolve_mut(parents)?, last) {
+            (Self::Map(map), PathSegment::Key(key)) => map.0.remove(key)
+                .ok_or_else(|| PathError::MissingKey { path: resolved, segment: last.to_string() }),
+            (Self::List(list), PathSegment::Index(index)) if *index < list.len() => Ok(list.remove(*index)),
+            (Self::List(list), PathSegment::Index(index)) => Err(PathError::IndexOutOfRange { path: resolved, index: *index, len: list.len() }),
+            (parent, segment) => Err(PathError::TypeMismatch { path: resolved, segment: segment.to_string(), found: parent.kind() }),
+        }
+    }
+}
+
+/// One step of a path into a document: a dictionary key or a list index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "[{}]", index),
+            Self::Key(key) => {
+                let key = String::from_utf8_lossy(key);
+                if key.is_empty() || key.contains(['.', '[', ']', '"']) {
+                    write!(f, "[{:?}]", key)
+                } else {
+                    write!(f, "{}", key)
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PathError {
+    #[error("invalid path at character {position}: {reason}")]
+    Syntax { position: usize, reason: &'static str },
+    #[error("key {segment} not found in {}", display_path(.path))]
+    MissingKey { path: String, segment: String },
+    #[error("index {index} is out of range in {}, which has {len} elements", display_path(.path))]
+    IndexOutOfRange { path: String, index: usize, len: usize },
+    #[error("can't look up {segment} in {}, which is {found}", display_path(.path))]
+    TypeMismatch { path: String, segment: String, found: &'static str },
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "<root>" } else { path }
+}
+
+pub fn format_path(segments: &[PathSegment]) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        let segment = segment.to_string();
+        if !path.is_empty() && !segment.starts_with('[') {
+            path.push('.');
+        }
+        path.push_str(&segment);
+    }
+    path
+}
+
+/// Parses a path made of dictionary keys separated by `.` and list indexes in brackets, like
+/// `info.files[3].path`. Keys that contain `.`, `[`, `]` or `"` can be quoted inside brackets: `["a.b"]`.
+/// The empty path refers to the whole document.
+pub fn parse_path(path: &str) -> Result<Vec<PathSegment>, PathError> {
+    let chars: Vec<char> = path.chars().collect();
+    let mut segments = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '[' => {
+                i += 1;
+                if chars.get(i) == Some(&'"') {
+                    i += 1;
+                    let mut key = String::new();
+                    loop {
+                        match chars.get(i) {
+                            Some('"') => break,
+                            Some('\\') if i + 1 < chars.len() => {
+                                key.push(chars[i + 1]);
+                                i += 2;
+                            },
+                            Some(c) => {
+                                key.push(*c);
+                                i += 1;
+                            },
+                            None => return Err(PathError::Syntax { position: i, reason: "unterminated quoted key" }),
+                        }
+                    }
+                    i += 1;
+                    segments.push(PathSegment::Key(key.into_bytes()));
+                } else {
+                    let start = i;
+                    while chars.get(i).is_some_and(|c| c.is_ascii_digit()) {
+                        i += 1;
+                    }
+                    let digits: String = chars[start..i].iter().collect();
+                    let index = digits.parse().map_err(|_| PathError::Syntax { position: start, reason: "expected a list index or a quoted key" })?;
+                    segments.push(PathSegment::Index(index));
+                }
+                if chars.get(i) != Some(&']') {
+                    return Err(PathError::Syntax { position: i, reason: "expected ']'" });
+                }
+                i += 1;
+            },
+            '.' if segments.is_empty() || i + 1 == chars.len() || chars[i + 1] == '.' => {
+                return Err(PathError::Syntax { position: i, reason: "empty key" });
+            },
+            '.' => i += 1,
+            ']' => return Err(PathError::Syntax { position: i, reason: "unexpected ']'" }),
+            _ => {
+                if i > 0 && chars[i - 1] != '.' {
+                    return Err(PathError::Syntax { position: i, reason: "expected '.' or '[' before a key" });
+                }
+                let start = i;
+                while i < chars.len() && chars[i] != '.' && chars[i] != '[' && chars[i] != ']' {
+                    i += 1;
+                }
+                let key: String = chars[start..i].iter().collect();
+                segments.push(PathSegment::Key(key.into_bytes()));
+            },
+        }
+    }
+    Ok(segments)
 }
 
+/// Renders the value as JSON, using the lossless form from [`value_to_json`].
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Self::String(_) = self {
-            return write!(f, "\"{}\"", self.to_string())
+        write!(f, "{}", value_to_json(self))
+    }
+}
+
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        i64::try_from(value).map(Self::Int).unwrap_or_else(|_| Self::BigInt(BigInt(value.to_string())))
+    }
+}
+
+impl From<BigInt> for Value {
+    fn from(value: BigInt) -> Self {
+        value.to_i64().map(Self::Int).unwrap_or(Self::BigInt(value))
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<u8> for Value {
+    fn from(value: u8) -> Self {
+        Self::Int(value.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::String(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::String(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Self::String(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(value: Vec<u8>) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Self::List(value)
+    }
+}
+
+impl From<Map> for Value {
+    fn from(value: Map) -> Self {
+        Self::Map(value)
+    }
+}
+
+/// Builds a [`Value`] from JSON-like syntax: `bencode!({ "m": { "ut_metadata": 1 } })`.
+/// Dictionary keys are strings, and anything else is converted with `Value::from`; wrap expressions
+/// that span more than one token, such as `-1` or `a + b`, in parentheses.
+#[macro_export]
+macro_rules! bencode {
+    ({ $($key:tt : $value:tt),* $(,)? }) => {{
+        #[allow(unused_mut)]
+        let mut map = $crate::modules::value::Map::new();
+        $( map.insert($key, $crate::bencode!($value)); )*
+        $crate::modules::value::Value::Map(map)
+    }};
+    ([ $($value:tt),* $(,)? ]) => {
+        $crate::modules::value::Value::List(vec![$($crate::bencode!($value)),*])
+    };
+    ($value:expr) => {
+        $crate::modules::value::Value::from($value)
+    };
+}
+
+/// Borrowed counterpart of [`Map`] whose keys slice into the decoded buffer.
+/// Each value also keeps the exact bytes it was decoded from.
+#[derive(Debug, Clone, Default)]
+pub struct MapRef<'a>(BTreeMap<&'a [u8], (ValueRef<'a>, &'a [u8])>);
+
+impl<'a> MapRef<'a> {
+    pub fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+    pub fn insert(&mut self, k: &'a [u8], v: ValueRef<'a>, raw: &'a [u8]) {
+        self.0.insert(k, (v, raw));
+    }
+    pub fn get(&self, key: &str) -> Option<&ValueRef<'a>> {
+        self.0.get(key.as_bytes()).map(|(value, _)| value)
+    }
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.0.contains_key(key)
+    }
+    /// Returns the original encoded bytes of the value stored under `key`.
+    pub fn get_raw(&self, key: &str) -> Option<&'a [u8]> {
+        self.0.get(key.as_bytes()).map(|(_, raw)| *raw)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &ValueRef<'a>)> {
+        self.0.iter().map(|(key, (value, _))| (*key, value))
+    }
+    pub fn to_owned_map(&self) -> Map {
+        let mut map = Map::new();
+        for (k, (v, _)) in &self.0 {
+            map.insert(k.to_vec(), v.to_owned_value());
         }
-        write!(f, "{}", self.to_string())
+        map
     }
-}
\ No newline at end of file
+}
+
+/// Borrowed counterpart of [`Value`]: byte strings point into the original input instead of being copied.
+#[derive(Debug, Clone)]
+pub enum ValueRef<'a> {
+    String(&'a [u8]),
+    Int(i64),
+    BigInt(BigInt),
+    List(Vec<Self>),
+    Map(MapRef<'a>),
+}
+
+impl ValueRef<'_> {
+    pub fn to_owned_value(&self) -> Value {
+        match self {
+            Self::String(s) => Value::String(s.to_vec()),
+            Self::Int(i) => Value::Int(*i),
+            Self::BigInt(i) => Value::BigInt(i.clone()),
+            Self::List(list) => Value::List(list.iter().map(|v| v.to_owned_value()).collect()),
+            Self::Map(map) => Value::Map(map.to_owned_map()),
+        }
+    }
+}
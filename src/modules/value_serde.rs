@@ -0,0 +1,860 @@
+use std::{collections::btree_map, fmt, slice, vec};
+
+use serde::{de::{self, DeserializeOwned, IntoDeserializer, Visitor}, ser::{self, Serialize}, Deserialize, Deserializer, Serializer};
+
+use crate::modules::{value::{BigInt, Map, Value, ValueRef}};
+
+/// Error from converting between [`Value`] and a Rust type, with the path of the offending field.
+#[derive(Debug)]
+pub struct Error {
+    path: Vec<String>,
+    message: String,
+}
+
+impl Error {
+    /// Records that the error happened inside `segment`, which is either a dictionary key or a `[index]`.
+    pub fn prefixed(mut self, segment: impl Into<String>) -> Self {
+        self.path.insert(0, segment.into());
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut path = String::new();
+        for segment in &self.path {
+            if !path.is_empty() && !segment.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(segment);
+        }
+        if path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self { path: vec![], message: msg.to_string() }
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self { path: vec![], message: msg.to_string() }
+    }
+}
+
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+/// Deserializes straight from a borrowed value, without copying it into a [`Value`] first.
+pub fn from_value_ref<'de, T: Deserialize<'de>>(value: &'de ValueRef<'de>) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(ValueSerializer)?.ok_or_else(|| ser::Error::custom("top-level value can't be empty"))
+}
+
+fn unexpected(value: &Value) -> de::Unexpected<'_> {
+    match value {
+        Value::String(s) => de::Unexpected::Bytes(s),
+        Value::Int(i) => de::Unexpected::Signed(*i),
+        Value::BigInt(_) => de::Unexpected::Other("an integer outside the 64-bit range"),
+        Value::List(_) => de::Unexpected::Seq,
+        Value::Map(_) => de::Unexpected::Map,
+    }
+}
+
+fn unexpected_ref<'a>(value: &ValueRef<'a>) -> de::Unexpected<'a> {
+    match value {
+        ValueRef::String(s) => de::Unexpected::Bytes(s),
+        ValueRef::Int(i) => de::Unexpected::Signed(*i),
+        ValueRef::BigInt(_) => de::Unexpected::Other("an integer outside the 64-bit range"),
+        ValueRef::List(_) => de::Unexpected::Seq,
+        ValueRef::Map(_) => de::Unexpected::Map,
+    }
+}
+
+macro_rules! deserialize_integers {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.deserialize_integer(visitor)
+            }
+        )*
+    };
+}
+
+impl Value {
+    // Like `deserialize_any`, but names the number when it's too wide for any Rust integer type.
+    fn deserialize_integer<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::BigInt(i) if i.as_str().parse::<i128>().is_err() && i.as_str().parse::<u128>().is_err() => {
+                Err(de::Error::invalid_value(de::Unexpected::Other(&format!("integer {}", i)), &visitor))
+            },
+            other => other.deserialize_any(visitor),
+        }
+    }
+}
+
+impl<'de> Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(err) => visitor.visit_byte_buf(err.into_bytes()),
+            },
+            Value::Int(i) => visitor.visit_i64(i),
+            // serde's widest integers are 128 bits, past that the digits are handed over in a newtype
+            // that only `Value`'s own visitor understands
+            Value::BigInt(i) => {
+                if let Ok(v) = i.as_str().parse::<u64>() {
+                    visitor.visit_u64(v)
+                } else if let Ok(v) = i.as_str().parse::<i128>() {
+                    visitor.visit_i128(v)
+                } else if let Ok(v) = i.as_str().parse::<u128>() {
+                    visitor.visit_u128(v)
+                } else {
+                    visitor.visit_newtype_struct(Value::String(i.to_string().into_bytes()))
+                }
+            },
+            Value::List(list) => visitor.visit_seq(SeqDeserializer { iter: list.into_iter(), index: 0 }),
+            Value::Map(map) => visitor.visit_map(MapDeserializer { iter: map.into_iter(), pending: None }),
+        }
+    }
+
+    // bencode has no booleans, flags like `private` are the integers 0 and 1
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Int(0) => visitor.visit_bool(false),
+            Value::Int(1) => visitor.visit_bool(true),
+            other => Err(de::Error::invalid_type(unexpected(&other), &"0 or 1")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::String(s) => match String::from_utf8(s) {
+                Ok(s) => visitor.visit_string(s),
+                Err(err) => Err(de::Error::invalid_value(de::Unexpected::Bytes(err.as_bytes()), &"a UTF-8 string")),
+            },
+            other => Err(de::Error::invalid_type(unexpected(&other), &visitor)),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::String(s) => visitor.visit_byte_buf(s),
+            other => Err(de::Error::invalid_type(unexpected(&other), &visitor)),
+        }
+    }
+
+    // a key that is present is never null, missing ones are handled by the derived code
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::String(s) => {
+                let variant = String::from_utf8(s)
+                    .map_err(|err| <Error as de::Error>::invalid_value(de::Unexpected::Bytes(err.as_bytes()), &"a UTF-8 variant name"))?;
+                visitor.visit_enum(variant.into_deserializer())
+            },
+            Value::Map(map) => {
+                let mut iter = map.into_iter();
+                match (iter.next(), iter.next()) {
+                    (Some((variant, value)), None) => visitor.visit_enum(EnumDeserializer { variant, value }),
+                    _ => Err(de::Error::invalid_value(de::Unexpected::Map, &"a dictionary with a single key")),
+                }
+            },
+            other => Err(de::Error::invalid_type(unexpected(&other), &"a string or a single-key dictionary")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    deserialize_integers! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+    }
+
+    serde::forward_to_deserialize_any! {
+        f32 f64 char
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct SeqDeserializer {
+    iter: vec::IntoIter<Value>,
+    index: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        let Some(value) = self.iter.next() else {
+            return Ok(None);
+        };
+        let index = self.index;
+        self.index += 1;
+        seed.deserialize(value).map(Some).map_err(|err| err.prefixed(format!("[{}]", index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapDeserializer {
+    iter: btree_map::IntoIter<Vec<u8>, Value>,
+    // entry whose key was handed out by next_key_seed and whose value is still to be deserialized
+    pending: Option<(Vec<u8>, Value)>,
+}
+
+impl<'de> de::MapAccess<'de> for MapDeserializer {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.iter.next() else {
+            return Ok(None);
+        };
+        let result = seed.deserialize(Value::String(key.clone()));
+        self.pending = Some((key, value));
+        result.map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, value) = self.pending.take().ok_or_else(|| <Error as de::Error>::custom("value requested before key"))?;
+        seed.deserialize(value).map_err(|err| err.prefixed(String::from_utf8_lossy(&key)))
+    }
+}
+
+struct EnumDeserializer {
+    variant: Vec<u8>,
+    value: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = Error;
+    type Variant = VariantDeserializer;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantDeserializer), Error> {
+        let name = String::from_utf8_lossy(&self.variant).into_owned();
+        let variant = seed.deserialize(Value::String(self.variant))?;
+        Ok((variant, VariantDeserializer { name, value: self.value }))
+    }
+}
+
+struct VariantDeserializer {
+    name: String,
+    value: Value,
+}
+
+impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Err(de::Error::invalid_type(unexpected(&self.value), &"a unit variant"))
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.value).map_err(|err| err.prefixed(self.name))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.value.deserialize_seq(visitor).map_err(|err| err.prefixed(self.name))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        self.value.deserialize_map(visitor).map_err(|err| err.prefixed(self.name))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = Value;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a bencode value")
+            }
+
+            fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+                Ok(Value::Int(v as i64))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+                Ok(Value::Int(v))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+                Ok(Value::from(v))
+            }
+
+            fn visit_i128<E>(self, v: i128) -> Result<Value, E> {
+                Ok(big_int(v))
+            }
+
+            fn visit_u128<E>(self, v: u128) -> Result<Value, E> {
+                Ok(big_int(v))
+            }
+
+            fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+                let digits = String::deserialize(deserializer)?;
+                let big = digits.parse::<BigInt>().map_err(de::Error::custom)?;
+                Ok(Value::from(big))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+                Ok(Value::String(v.as_bytes().to_vec()))
+            }
+
+            fn visit_string<E>(self, v: String) -> Result<Value, E> {
+                Ok(Value::String(v.into_bytes()))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+                Ok(Value::String(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Value, E> {
+                Ok(Value::String(v))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+                let mut list = vec![];
+                while let Some(value) = seq.next_element()? {
+                    list.push(value);
+                }
+                Ok(Value::List(list))
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut access: A) -> Result<Value, A::Error> {
+                let mut map = Map::new();
+                while let Some((key, value)) = access.next_entry::<serde_bytes::ByteBuf, Value>()? {
+                    map.insert(key.into_vec(), value);
+                }
+                Ok(Value::Map(map))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::String(s) => serializer.serialize_bytes(s),
+            Value::Int(i) => serializer.serialize_i64(*i),
+            Value::BigInt(i) => {
+                if let Ok(v) = i.as_str().parse::<u64>() {
+                    serializer.serialize_u64(v)
+                } else if let Ok(v) = i.as_str().parse::<i128>() {
+                    serializer.serialize_i128(v)
+                } else if let Ok(v) = i.as_str().parse::<u128>() {
+                    serializer.serialize_u128(v)
+                } else {
+                    serializer.serialize_newtype_struct(BIG_INT_NAME, i.as_str())
+                }
+            },
+            Value::List(list) => serializer.collect_seq(list),
+            Value::Map(map) => serializer.collect_map(map.iter().map(|(k, v)| (serde_bytes::Bytes::new(k), v))),
+        }
+    }
+}
+
+// Newtype name `Value` uses for integers too wide for serde's data model, see `serialize_newtype_struct`.
+const BIG_INT_NAME: &str = "$bencode::BigInt";
+
+fn big_int(v: impl fmt::Display) -> Value {
+    let big = v.to_string().parse::<BigInt>().expect("integers format as decimal digits");
+    Value::from(big)
+}
+
+/// Serializes Rust values into [`Value`]. `None` produces no value, so optional dictionary entries are left out.
+struct ValueSerializer;
+
+fn key_error() -> Error {
+    ser::Error::custom("dictionary keys must be strings")
+}
+
+impl Serializer for ValueSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = VariantSerializer<SeqSerializer>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = VariantSerializer<MapSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::Int(v as i64)))
+    }
+    fn serialize_i8(self, v: i8) -> Result<Option<Value>, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i16(self, v: i16) -> Result<Option<Value>, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i32(self, v: i32) -> Result<Option<Value>, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i64(self, v: i64) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::Int(v)))
+    }
+    fn serialize_u8(self, v: u8) -> Result<Option<Value>, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_u16(self, v: u16) -> Result<Option<Value>, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_u32(self, v: u32) -> Result<Option<Value>, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_u64(self, v: u64) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::from(v)))
+    }
+    fn serialize_i128(self, v: i128) -> Result<Option<Value>, Error> {
+        Ok(Some(big_int(v)))
+    }
+    fn serialize_u128(self, v: u128) -> Result<Option<Value>, Error> {
+        Ok(Some(big_int(v)))
+    }
+    fn serialize_f32(self, _v: f32) -> Result<Option<Value>, Error> {
+        Err(ser::Error::custom("bencode has no floating point numbers"))
+    }
+    fn serialize_f64(self, _v: f64) -> Result<Option<Value>, Error> {
+        Err(ser::Error::custom("bencode has no floating point numbers"))
+    }
+    fn serialize_char(self, v: char) -> Result<Option<Value>, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+    fn serialize_str(self, v: &str) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::String(v.as_bytes().to_vec())))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::String(v.to_vec())))
+    }
+    fn serialize_none(self) -> Result<Option<Value>, Error> {
+        Ok(None)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Option<Value>, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Option<Value>, Error> {
+        Ok(None)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Option<Value>, Error> {
+        Ok(None)
+    }
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Option<Value>, Error> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, name: &'static str, value: &T) -> Result<Option<Value>, Error> {
+        match value.serialize(self)? {
+            Some(Value::String(digits)) if name == BIG_INT_NAME => {
+                let big = BigInt::parse(&digits).ok_or_else(|| <Error as ser::Error>::custom("invalid big integer"))?;
+                Ok(Some(Value::from(big)))
+            },
+            value => Ok(value),
+        }
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<Option<Value>, Error> {
+        let mut map = Map::new();
+        if let Some(value) = value.serialize(ValueSerializer).map_err(|err| err.prefixed(variant))? {
+            map.insert(variant.as_bytes().to_vec(), value);
+        }
+        Ok(Some(Value::Map(map)))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize) -> Result<VariantSerializer<SeqSerializer>, Error> {
+        Ok(VariantSerializer { variant, inner: self.serialize_seq(Some(len))? })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer, Error> {
+        Ok(MapSerializer { map: Map::new(), key: None })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer, Error> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize) -> Result<VariantSerializer<MapSerializer>, Error> {
+        Ok(VariantSerializer { variant, inner: self.serialize_map(Some(len))? })
+    }
+}
+
+struct SeqSerializer(Vec<Value>);
+
+impl SeqSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let index = self.0.len();
+        let value = value.serialize(ValueSerializer)
+            .and_then(|value| value.ok_or_else(|| ser::Error::custom("list elements can't be empty")))
+            .map_err(|err| err.prefixed(format!("[{}]", index)))?;
+        self.0.push(value);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::List(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::List(self.0)))
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::List(self.0)))
+    }
+}
+
+struct MapSerializer {
+    map: Map,
+    key: Option<Vec<u8>>,
+}
+
+impl MapSerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        let value = value.serialize(ValueSerializer).map_err(|err| err.prefixed(String::from_utf8_lossy(&key)))?;
+        if let Some(value) = value {
+            self.map.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(ValueSerializer)? {
+            Some(Value::String(key)) => {
+                self.key = Some(key);
+                Ok(())
+            },
+            _ => Err(key_error()),
+        }
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().ok_or_else(|| <Error as ser::Error>::custom("value serialized before key"))?;
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::Map(self.map)))
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Ok(Some(Value::Map(self.map)))
+    }
+}
+
+/// Wraps the serializer of an enum variant's contents into a single-key dictionary named after the variant.
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+impl<S> VariantSerializer<S> {
+    fn wrap(variant: &'static str, value: Option<Value>) -> Result<Option<Value>, Error> {
+        let mut map = Map::new();
+        if let Some(value) = value {
+            map.insert(variant.as_bytes().to_vec(), value);
+        }
+        Ok(Some(Value::Map(map)))
+    }
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<SeqSerializer> {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.inner.push(value).map_err(|err| err.prefixed(self.variant))
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Self::wrap(self.variant, Some(Value::List(self.inner.0)))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<MapSerializer> {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.inner.insert(key.as_bytes().to_vec(), value).map_err(|err| err.prefixed(self.variant))
+    }
+    fn end(self) -> Result<Option<Value>, Error> {
+        Self::wrap(self.variant, Some(Value::Map(self.inner.map)))
+    }
+}
+
+// The borrowed deserializer mirrors the owned one above, but hands out byte strings that point into the input.
+impl<'de> Deserializer<'de> for &'de ValueRef<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            ValueRef::String(s) => match std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(s),
+            },
+            ValueRef::Int(i) => visitor.visit_i64(*i),
+            ValueRef::BigInt(i) => Value::BigInt(i.clone()).deserialize_any(visitor),
+            ValueRef::List(list) => visitor.visit_seq(SeqRefDeserializer { iter: list.iter(), index: 0 }),
+            ValueRef::Map(map) => visitor.visit_map(MapRefDeserializer { iter: map.iter(), pending: None }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            ValueRef::Int(0) => visitor.visit_bool(false),
+            ValueRef::Int(1) => visitor.visit_bool(true),
+            other => Err(de::Error::invalid_type(unexpected_ref(other), &"0 or 1")),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            ValueRef::String(s) => match std::str::from_utf8(s) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Bytes(s), &"a UTF-8 string")),
+            },
+            other => Err(de::Error::invalid_type(unexpected_ref(other), &visitor)),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            ValueRef::String(s) => visitor.visit_borrowed_bytes(s),
+            other => Err(de::Error::invalid_type(unexpected_ref(other), &visitor)),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // enums are rare in metainfo, so they go through an owned copy
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        self.to_owned_value().deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    deserialize_integers! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+    }
+
+    serde::forward_to_deserialize_any! {
+        f32 f64 char
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+impl<'de> ValueRef<'de> {
+    fn deserialize_integer<V: Visitor<'de>>(&'de self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            ValueRef::BigInt(i) => Value::BigInt(i.clone()).deserialize_integer(visitor),
+            other => other.deserialize_any(visitor),
+        }
+    }
+}
+
+struct SeqRefDeserializer<'de> {
+    iter: slice::Iter<'de, ValueRef<'de>>,
+    index: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqRefDeserializer<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        let Some(value) = self.iter.next() else {
+            return Ok(None);
+        };
+        let index = self.index;
+        self.index += 1;
+        seed.deserialize(value).map(Some).map_err(|err| err.prefixed(format!("[{}]", index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct MapRefDeserializer<'de, I> {
+    iter: I,
+    pending: Option<(&'de [u8], &'de ValueRef<'de>)>,
+}
+
+impl<'de, I: Iterator<Item = (&'de [u8], &'de ValueRef<'de>)>> de::MapAccess<'de> for MapRefDeserializer<'de, I> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.iter.next() else {
+            return Ok(None);
+        };
+        self.pending = Some((key, value));
+        seed.deserialize(de::value::BorrowedBytesDeserializer::<Error>::new(key)).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, value) = self.pending.take().ok_or_else(|| <Error as de::Error>::custom("value requested before key"))?;
+        seed.deserialize(value).map_err(|err| err.prefixed(String::from_utf8_lossy(key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+    use crate::{bencode, modules::bencode::decode_bencoded_ref};
+
+    #[derive(Debug, Deserialize)]
+    struct File {
+        length: u64,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Files {
+        files: Vec<File>,
+    }
+
+    #[test]
+    fn prefixes_errors_with_the_field_path() {
+        let files = from_value::<Files>(bencode!({ "files": [{ "length": 1 }] })).unwrap();
+        assert_eq!(files.files[0].length, 1);
+
+        let missing = bencode!({ "files": [{ "length": 1 }, {}] });
+        assert_eq!(from_value::<Files>(missing).unwrap_err().to_string(), "files[1]: missing field `length`");
+
+        let mistyped = bencode!({ "files": [{ "length": 1 }, { "length": "two" }] });
+        let err = from_value::<Files>(mistyped).unwrap_err().to_string();
+        assert!(err.starts_with("files[1].length: invalid type"), "{}", err);
+
+        let (value, _) = decode_bencoded_ref(b"d5:filesld6:lengthi-1eeee").unwrap();
+        let err = from_value_ref::<Files>(&value).unwrap_err().to_string();
+        assert!(err.starts_with("files[0].length: invalid value"), "{}", err);
+    }
+
+    #[test]
+    fn names_integers_too_wide_for_the_field() {
+        let huge = "123456789012345678901234567890123456789012345678901234567890";
+        let err = from_value::<u128>(Value::from(huge.parse::<BigInt>().unwrap())).unwrap_err().to_string();
+        assert!(err.contains(huge), "{}", err);
+        assert_eq!(from_value::<i128>(Value::from("-170141183460469231731687303715884105728".parse::<BigInt>().unwrap())).unwrap(), i128::MIN);
+    }
+
+    #[test]
+    fn round_trips_big_integers_through_serde() {
+        for digits in ["18446744073709551615", "-170141183460469231731687303715884105728", "340282366920938463463374607431768211455", "-123456789012345678901234567890123456789012345678901234567890"] {
+            let value = Value::from(digits.parse::<BigInt>().unwrap());
+            assert_eq!(to_value(&value).unwrap(), value, "{}", digits);
+            assert_eq!(from_value::<Value>(value.clone()).unwrap(), value, "{}", digits);
+        }
+        assert_eq!(to_value(&u128::MAX).unwrap(), Value::from(u128::MAX.to_string().parse::<BigInt>().unwrap()));
+    }
+
+    #[test]
+    fn leaves_out_none_and_rejects_floats() {
+        #[derive(Serialize)]
+        struct Entry {
+            name: Option<&'static str>,
+            size: Option<i64>,
+        }
+        #[derive(Serialize)]
+        struct Sizes {
+            sizes: Vec<f64>,
+        }
+
+        assert_eq!(to_value(&Entry { name: Some("a"), size: None }).unwrap(), bencode!({ "name": "a" }));
+        assert_eq!(to_value(&None::<i64>).unwrap_err().to_string(), "top-level value can't be empty");
+        assert_eq!(to_value(&Sizes { sizes: vec![1.5] }).unwrap_err().to_string(), "sizes[0]: bencode has no floating point numbers");
+    }
+}
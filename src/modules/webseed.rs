@@ -0,0 +1,369 @@
+use std::{thread, time::Duration};
+
+use percent_encoding::{percent_encode, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{blocking::Client, header::RANGE, StatusCode};
+use thiserror::Error;
+
+use crate::modules::torrent::Info;
+
+// Characters left alone in URL path segments (RFC 3986 unreserved).
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+// How often an HTTP seed is asked again after telling us to come back later, and how long we wait at most.
+const HTTP_SEED_ATTEMPTS: usize = 3;
+const MAX_RETRY_AFTER: u64 = 30;
+
+#[derive(Debug, Error)]
+pub enum WebSeedError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{url}: expected {expected} bytes, got {received}")]
+    ShortResponse { url: String, expected: u64, received: u64 },
+    #[error("no piece {0}")]
+    NoSuchPiece(usize),
+    #[error("piece {0} doesn't match its hash")]
+    HashMismatch(usize),
+    #[error("{url}: busy, retry after {seconds} seconds")]
+    Busy { url: String, seconds: u64 },
+}
+
+/// An HTTP server mirroring the torrent's content, as listed in `url-list` (BEP 19).
+pub struct WebSeed {
+    url: String,
+    client: Client,
+}
+
+impl WebSeed {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string(), client: Client::new() }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // A single-file torrent's URL is the file itself, unless it ends in a slash and names the directory
+    // holding it. For a multi-file torrent it's the directory holding the torrent's root directory.
+    fn file_url(&self, info: &Info, path: &[String]) -> String {
+        let mut url = self.url.clone();
+        if !info.is_multi_file() && !url.ends_with('/') {
+            return url;
+        }
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let mut segments = vec![info.name()];
+        if info.is_multi_file() {
+            segments.extend(path.iter().map(String::as_str));
+        }
+        let segments: Vec<String> = segments.iter().map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string()).collect();
+        url + &segments.join("/")
+    }
+
+    /// Fetches the piece with one Range request per file it spans and checks it against its hash.
+    /// Padding files (BEP 47) aren't on the server and are filled in with zeros.
+    pub fn download_piece(&self, info: &Info, piece_index: usize) -> Result<Vec<u8>, WebSeedError> {
+        let piece = info.piece_range(piece_index).ok_or(WebSeedError::NoSuchPiece(piece_index))?;
+        let mut data = Vec::with_capacity((piece.end - piece.start) as usize);
+        for file in info.files() {
+            let file_end = file.offset + file.length;
+            if file.length == 0 || file_end <= piece.start || file.offset >= piece.end {
+                continue;
+            }
+            let start = piece.start.max(file.offset) - file.offset;
+            let end = piece.end.min(file_end) - file.offset;
+            if file.attributes.padding {
+                data.resize(data.len() + (end - start) as usize, 0);
+                continue;
+            }
+            data.extend(self.fetch_range(&self.file_url(info, &file.path), start, end)?);
+        }
+        if !info.verify_piece(piece_index, &data) {
+            return Err(WebSeedError::HashMismatch(piece_index));
+        }
+        Ok(data)
+    }
+
+    fn fetch_range(&self, url: &str, start: u64, end: u64) -> Result<Vec<u8>, WebSeedError> {
+        let response = self.client.get(url)
+            .header(RANGE, format!("bytes={}-{}", start, end - 1))
+            .send()
+            .and_then(|response| response.error_for_status())?;
+        // a server that ignores Range sends the whole file
+        let skip = if response.status() == StatusCode::PARTIAL_CONTENT { 0 } else { start as usize };
+        let body = response.bytes()?;
+        let expected = end - start;
+        let received = (body.len().saturating_sub(skip) as u64).min(expected);
+        if received < expected {
+            return Err(WebSeedError::ShortResponse { url: url.to_string(), expected, received });
+        }
+        Ok(body[skip..skip + expected as usize].to_vec())
+    }
+}
+
+/// A seeding script as listed in `httpseeds` (BEP 17), serving whole pieces by index.
+pub struct HttpSeed {
+    url: String,
+    client: Client,
+    // waits out a retry-after reply, swapped out by tests
+    sleep: fn(Duration),
+}
+
+impl HttpSeed {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string(), client: Client::new(), sleep: thread::sleep }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Fetches the piece and checks it against its hash. A busy seed answers 503 with the number of
+    /// seconds to wait as the body; short waits are honored a few times before giving up.
+    pub fn download_piece(&self, info: &Info, piece_index: usize) -> Result<Vec<u8>, WebSeedError> {
+        let piece = info.piece_range(piece_index).ok_or(WebSeedError::NoSuchPiece(piece_index))?;
+        // ranges are inclusive, so an empty piece has no range to ask for
+        let last = (piece.end - piece.start).checked_sub(1).ok_or(WebSeedError::NoSuchPiece(piece_index))?;
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}info_hash={}&piece={}&ranges=0-{}", self.url, separator,
+            percent_encode(&info.get_info_hash_bytes(), NON_ALPHANUMERIC), piece_index, last);
+        let mut attempt = 1;
+        let data = loop {
+            let response = self.client.get(&url).send().map_err(reqwest::Error::without_url)?;
+            if response.status() != StatusCode::SERVICE_UNAVAILABLE {
+                break response.error_for_status().and_then(|response| response.bytes()).map_err(reqwest::Error::without_url)?;
+            }
+            let seconds = response.text().map_err(reqwest::Error::without_url)?.trim().parse().unwrap_or(MAX_RETRY_AFTER);
+            if attempt == HTTP_SEED_ATTEMPTS || seconds > MAX_RETRY_AFTER {
+                return Err(WebSeedError::Busy { url: self.url.clone(), seconds });
+            }
+            (self.sleep)(Duration::from_secs(seconds));
+            attempt += 1;
+        };
+        let (expected, received) = (piece.end - piece.start, data.len() as u64);
+        if received != expected {
+            return Err(WebSeedError::ShortResponse { url: self.url.clone(), expected, received });
+        }
+        if !info.verify_piece(piece_index, &data) {
+            return Err(WebSeedError::HashMismatch(piece_index));
+        }
+        Ok(data.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::{BufRead, BufReader, Write}, net::TcpListener, sync::{Arc, Mutex}, thread};
+
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::{bencode, modules::{bencode::{decode_bencoded_ref, encode_value}, torrent::Torrent, value::Value}};
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        Range,
+        IgnoreRange,
+        ShortBody,
+    }
+
+    type Requests = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    // Serves `files` by URL path on a local port, recording each request's path and Range header.
+    fn serve(files: HashMap<&'static str, Vec<u8>>, mode: Mode) -> (String, Requests) {
+        serve_with(move |path, range| match (files.get(path), range, mode) {
+            (None, _, _) => ("404 Not Found", vec![]),
+            (Some(data), Some(range), Mode::Range | Mode::ShortBody) => {
+                let (start, end) = range.split_once('-').unwrap();
+                let mut body = data[start.parse::<usize>().unwrap()..=end.parse::<usize>().unwrap()].to_vec();
+                if matches!(mode, Mode::ShortBody) {
+                    body.pop();
+                }
+                ("206 Partial Content", body)
+            },
+            (Some(data), _, _) => ("200 OK", data.clone()),
+        })
+    }
+
+    // Answers each request with the status and body `respond` picks for its path and Range header.
+    fn serve_with(mut respond: impl FnMut(&str, Option<&str>) -> (&'static str, Vec<u8>) + Send + 'static) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap().to_string();
+                let mut range = None;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = header.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        range = Some(value.trim().to_string());
+                    }
+                }
+                log.lock().unwrap().push((path.clone(), range.clone()));
+                let (status, body) = respond(&path, range.as_deref());
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    // Adds piece hashes over `content` to `info` and parses the result.
+    fn torrent(info: Value, content: &[u8], piece_length: usize) -> Torrent {
+        let Value::Map(mut info) = info else {
+            unreachable!("tests pass a dictionary")
+        };
+        let pieces: Vec<u8> = content.chunks(piece_length).flat_map(|piece| Sha1::digest(piece).to_vec()).collect();
+        info.insert("piece length", piece_length as i64);
+        info.insert("pieces", pieces);
+        let encoded = encode_value(bencode!({ "info": (Value::Map(info)) }));
+        let (value, _) = decode_bencoded_ref(&encoded).unwrap();
+        Torrent::new(&value).unwrap()
+    }
+
+    fn content(length: u8) -> Vec<u8> {
+        (0..length).collect()
+    }
+
+    fn single_file(content: &[u8]) -> Torrent {
+        torrent(bencode!({ "name": "f.bin", "length": (content.len() as i64) }), content, 16)
+    }
+
+    #[test]
+    fn fetches_a_piece_with_a_range_request() {
+        let data = content(40);
+        let (url, requests) = serve(HashMap::from([("/f.bin", data.clone())]), Mode::Range);
+        let piece = WebSeed::new(&url).download_piece(&single_file(&data).info, 2).unwrap();
+        assert_eq!(piece, &data[32..]);
+        assert_eq!(*requests.lock().unwrap(), [("/f.bin".to_string(), Some("32-39".to_string()))]);
+    }
+
+    #[test]
+    fn cuts_the_piece_out_of_a_full_response() {
+        let data = content(40);
+        let (url, _) = serve(HashMap::from([("/f.bin", data.clone())]), Mode::IgnoreRange);
+        let piece = WebSeed::new(&url).download_piece(&single_file(&data).info, 1).unwrap();
+        assert_eq!(piece, &data[16..32]);
+    }
+
+    #[test]
+    fn rejects_a_short_response() {
+        let data = content(40);
+        let (url, _) = serve(HashMap::from([("/f.bin", data.clone())]), Mode::ShortBody);
+        let err = WebSeed::new(&url).download_piece(&single_file(&data).info, 0).unwrap_err();
+        assert!(matches!(err, WebSeedError::ShortResponse { expected: 16, received: 15, .. }), "{}", err);
+    }
+
+    #[test]
+    fn rejects_content_that_doesnt_match_the_hash() {
+        let data = content(40);
+        let (url, _) = serve(HashMap::from([("/f.bin", vec![0; 40])]), Mode::Range);
+        let err = WebSeed::new(&url).download_piece(&single_file(&data).info, 1).unwrap_err();
+        assert!(matches!(err, WebSeedError::HashMismatch(1)), "{}", err);
+    }
+
+    #[test]
+    fn rejects_a_piece_past_the_end() {
+        let data = content(10);
+        let (url, requests) = serve(HashMap::from([("/f.bin", data.clone())]), Mode::Range);
+        let err = WebSeed::new(&url).download_piece(&single_file(&data).info, 1).unwrap_err();
+        assert!(matches!(err, WebSeedError::NoSuchPiece(1)), "{}", err);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn splits_a_piece_across_files() {
+        let (a, b) = (content(10), content(20));
+        let torrent = torrent(bencode!({
+            "name": "my dir",
+            "files": [{ "length": 10, "path": ["a.bin"] }, { "length": 20, "path": ["sub", "b c.bin"] }],
+        }), &[a.clone(), b.clone()].concat(), 16);
+        let files = HashMap::from([("/my%20dir/a.bin", a.clone()), ("/my%20dir/sub/b%20c.bin", b.clone())]);
+        let (url, requests) = serve(files, Mode::Range);
+        let seed = WebSeed::new(&url);
+        assert_eq!(seed.download_piece(&torrent.info, 0).unwrap(), [&a[..], &b[..6]].concat());
+        assert_eq!(seed.download_piece(&torrent.info, 1).unwrap(), &b[6..]);
+        assert_eq!(*requests.lock().unwrap(), [
+            ("/my%20dir/a.bin".to_string(), Some("0-9".to_string())),
+            ("/my%20dir/sub/b%20c.bin".to_string(), Some("0-5".to_string())),
+            ("/my%20dir/sub/b%20c.bin".to_string(), Some("6-19".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn fills_pad_files_with_zeros_without_requesting_them() {
+        let (a, b) = (content(3), content(5));
+        let torrent = torrent(bencode!({
+            "name": "d",
+            "files": [
+                { "length": 3, "path": ["a"] },
+                { "attr": "p", "length": 13, "path": [".pad", "13"] },
+                { "length": 5, "path": ["b"] },
+            ],
+        }), &[&a[..], &[0; 13], &b[..]].concat(), 16);
+        let (url, requests) = serve(HashMap::from([("/d/a", a.clone()), ("/d/b", b.clone())]), Mode::Range);
+        let seed = WebSeed::new(&url);
+        assert_eq!(seed.download_piece(&torrent.info, 0).unwrap(), [&a[..], &[0; 13]].concat());
+        assert_eq!(seed.download_piece(&torrent.info, 1).unwrap(), b);
+        let paths: Vec<String> = requests.lock().unwrap().iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(paths, ["/d/a", "/d/b"]);
+    }
+
+    // Answers HTTP seed requests for `content` in pieces of 16 bytes, busy for the first `busy` of them.
+    fn serve_pieces(content: Vec<u8>, busy: usize) -> (String, Requests) {
+        let mut requests = 0;
+        serve_with(move |path, _| {
+            requests += 1;
+            if requests <= busy {
+                return ("503 Service Unavailable", b"5".to_vec());
+            }
+            let query: HashMap<&str, &str> = path.split_once('?').unwrap().1.split('&').filter_map(|pair| pair.split_once('=')).collect();
+            let piece: usize = query["piece"].parse().unwrap();
+            let (start, end) = query["ranges"].split_once('-').unwrap();
+            let piece = &content[piece * 16..];
+            ("200 OK", piece[start.parse::<usize>().unwrap()..=end.parse::<usize>().unwrap()].to_vec())
+        })
+    }
+
+    fn no_sleep(_: Duration) {}
+
+    #[test]
+    fn fetches_a_piece_from_an_http_seed() {
+        let data = content(40);
+        let torrent = single_file(&data);
+        let (url, requests) = serve_pieces(data.clone(), 0);
+        let seed = HttpSeed::new(&format!("{}seed.php?key=1", url));
+        assert_eq!(seed.download_piece(&torrent.info, 2).unwrap(), &data[32..]);
+        let info_hash = percent_encode(&torrent.info.get_info_hash_bytes(), NON_ALPHANUMERIC).to_string();
+        let expected = format!("/seed.php?key=1&info_hash={}&piece=2&ranges=0-7", info_hash);
+        assert_eq!(*requests.lock().unwrap(), [(expected, None)]);
+    }
+
+    #[test]
+    fn retries_a_busy_http_seed() {
+        let data = content(40);
+        let (url, requests) = serve_pieces(data.clone(), 2);
+        let seed = HttpSeed { sleep: no_sleep, ..HttpSeed::new(&url) };
+        assert_eq!(seed.download_piece(&single_file(&data).info, 1).unwrap(), &data[16..32]);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn gives_up_on_an_http_seed_that_stays_busy() {
+        let data = content(40);
+        let (url, requests) = serve_pieces(data.clone(), HTTP_SEED_ATTEMPTS);
+        let seed = HttpSeed { sleep: no_sleep, ..HttpSeed::new(&url) };
+        let err = seed.download_piece(&single_file(&data).info, 0).unwrap_err();
+        assert!(matches!(err, WebSeedError::Busy { seconds: 5, .. }), "{}", err);
+        assert_eq!(requests.lock().unwrap().len(), HTTP_SEED_ATTEMPTS);
+    }
+}